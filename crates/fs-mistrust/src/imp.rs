@@ -10,7 +10,7 @@ use std::os::unix::prelude::MetadataExt;
 
 use crate::{
     walk::{PathType, ResolvePath},
-    Error, Result, Type,
+    Error, Result, Type, Verifier,
 };
 
 /// Definition for the "sticky bit", which on Unix means that the contents of
@@ -34,6 +34,18 @@ impl<'a> super::Verifier<'a> {
     /// Return an iterator of all the security problems with `path`.
     ///
     /// If the iterator is empty, then there is no problem with `path`.
+    ///
+    /// # Limitations
+    ///
+    /// [`Mistrust::ignore_cross_device`](crate::Mistrust::ignore_cross_device)
+    /// is accepted but not yet enforced: nothing here actually raises
+    /// `Error::CrossDevice` when the walk crosses a filesystem boundary.
+    /// Doing so needs each step of the walk to know the device (`st_dev`)
+    /// its *parent* lives on so it can compare against its own, and
+    /// `ResolvePath` (in `walk.rs`) doesn't thread that along yet. Setting
+    /// `ignore_cross_device` currently has no effect either way -- it
+    /// doesn't suppress a check that isn't happening, but callers shouldn't
+    /// read its absence as "cross-device paths are being checked".
     //
     // TODO: This iterator is not fully lazy; sometimes, calls to check_one()
     // return multiple errors when it would be better for them to return only
@@ -127,56 +139,155 @@ impl<'a> super::Verifier<'a> {
             errors.push(Error::BadType(path.into()));
         }
 
-        // If we are on unix, make sure that the owner and permissions are
-        // acceptable.
+        // Check the owner and permissions of the object, using whatever
+        // notion of "owner" and "permissions" this platform has.  Keeping
+        // this cfg-free here means `check_one` doesn't grow a second set of
+        // platform branches every time we teach it about a new OS.
+        Backend::check_owner_and_permissions(self, path, path_type, meta, &mut errors);
+
+        // A trusted file can still be modified through a second hardlink
+        // sitting in some untrusted directory, so treat an unexpected extra
+        // link as a problem in its own right, independent of the owner and
+        // mode bits above.
         #[cfg(target_family = "unix")]
+        if !self.mistrust.ignore_hardlinks
+            && meta.is_file()
+            && matches!(path_type, PathType::Final | PathType::Content)
+            && meta.nlink() > 1
         {
-            // We need to check that the owner is trusted, since the owner can
-            // always change the permissions of the object.  (If we're talking
-            // about a directory, the owner cah change the permissions and owner
-            // of anything in the directory.)
-            let uid = meta.uid();
-            if uid != 0 && Some(uid) != self.mistrust.trust_uid {
-                errors.push(Error::BadOwner(path.into(), uid));
-            }
-            let mut forbidden_bits = if !self.readable_okay
-                && (path_type == PathType::Final || path_type == PathType::Content)
+            errors.push(Error::Hardlinked(path.into(), meta.nlink()));
+        }
+
+        // Cross-device detection is not implemented yet; see the
+        // `# Limitations` section on `check_errors`'s doc comment for why.
+        // `ignore_cross_device` is wired up on `Mistrust` ahead of that so
+        // callers can already build with it set, but reading it here is a
+        // no-op either way until the walk can thread `st_dev` through.
+        #[cfg(target_family = "unix")]
+        let _ = self.mistrust.ignore_cross_device;
+
+        errors
+    }
+}
+
+/// Platform-specific half of [`Verifier::check_one`]: deciding whether the
+/// owner and permissions (in whatever form the platform exposes them) of a
+/// single path are acceptable.
+///
+/// Each platform gets its own implementation of this trait, so that
+/// `check_one` itself never needs a `#[cfg(target_family = ...)]` of its own.
+trait PlatformChecks {
+    /// Check the owner and permissions of `path`/`meta`, appending any
+    /// problems found to `errors`.
+    ///
+    /// `path_type` and `verifier.readable_okay` together say whether `path`
+    /// is allowed to be readable by untrusted parties; see the call site in
+    /// [`Verifier::check_one`].
+    fn check_owner_and_permissions(
+        verifier: &Verifier<'_>,
+        path: &Path,
+        path_type: PathType,
+        meta: &Metadata,
+        errors: &mut Vec<Error>,
+    );
+}
+
+/// The platform backend actually used by [`Verifier::check_one`].
+#[cfg(target_family = "unix")]
+type Backend = UnixChecks;
+/// The platform backend actually used by [`Verifier::check_one`]: a no-op,
+/// for platforms (including Windows) where we don't yet know how to check
+/// ownership and permissions.
+///
+/// There used to be a `windows_checks`-feature-gated `WindowsChecks` backend
+/// here, but it was an empty stub: it couldn't actually read a security
+/// descriptor (no `windows-sys` dependency) or decide which SIDs are trusted
+/// (no Windows counterpart to `trust_uid`/`trust_gid`), so enabling the
+/// feature silently turned "verify this path" into "assume this path is
+/// fine" -- worse than not offering the feature at all. Route Windows
+/// through `NoChecks`, same as every other unsupported platform, until a real
+/// ACL-walking backend lands.
+#[cfg(not(target_family = "unix"))]
+type Backend = NoChecks;
+
+/// [`PlatformChecks`] backend for Unix-like platforms, using `uid`/`gid`/`mode`.
+#[cfg(target_family = "unix")]
+struct UnixChecks;
+
+#[cfg(target_family = "unix")]
+impl PlatformChecks for UnixChecks {
+    fn check_owner_and_permissions(
+        verifier: &Verifier<'_>,
+        path: &Path,
+        path_type: PathType,
+        meta: &Metadata,
+        errors: &mut Vec<Error>,
+    ) {
+        // We need to check that the owner is trusted, since the owner can
+        // always change the permissions of the object.  (If we're talking
+        // about a directory, the owner cah change the permissions and owner
+        // of anything in the directory.)
+        let uid = meta.uid();
+        if uid != 0 && Some(uid) != verifier.mistrust.trust_uid {
+            errors.push(Error::BadOwner(path.into(), uid));
+        }
+        let mut forbidden_bits = if !verifier.readable_okay
+            && (path_type == PathType::Final || path_type == PathType::Content)
+        {
+            // If this is the target or a content object, and it must not be
+            // readable, then we forbid it to be group-rwx and all-rwx.
+            0o077
+        } else {
+            // If this is the target object and it may be readable, or if
+            // this is _any parent directory_, then we typically forbid the
+            // group-write and all-write bits.  (Those are the bits that
+            // would allow non-trusted users to change the object, or change
+            // things around in a directory.)
+            if meta.is_dir() && meta.mode() & STICKY_BIT != 0 && path_type == PathType::Intermediate
             {
-                // If this is the target or a content object, and it must not be
-                // readable, then we forbid it to be group-rwx and all-rwx.
-                0o077
+                // This is an intermediate directory and this sticky bit is
+                // set.  Thus, we don't care if it is world-writable or
+                // group-writable, since only the _owner_  of a file in this
+                // directory can move or rename it.
+                0o000
             } else {
-                // If this is the target object and it may be readable, or if
-                // this is _any parent directory_, then we typically forbid the
-                // group-write and all-write bits.  (Those are the bits that
-                // would allow non-trusted users to change the object, or change
-                // things around in a directory.)
-                if meta.is_dir()
-                    && meta.mode() & STICKY_BIT != 0
-                    && path_type == PathType::Intermediate
-                {
-                    // This is an intermediate directory and this sticky bit is
-                    // set.  Thus, we don't care if it is world-writable or
-                    // group-writable, since only the _owner_  of a file in this
-                    // directory can move or rename it.
-                    0o000
-                } else {
-                    // It's not a sticky-bit intermediate directory; actually
-                    // forbid 022.
-                    0o022
-                }
-            };
-            // If we trust the GID, then we allow even more bits to be set.
-            if self.mistrust.trust_gid == Some(meta.gid()) {
-                forbidden_bits &= !0o070;
-            }
-            let bad_bits = meta.mode() & forbidden_bits;
-            if bad_bits != 0 {
-                errors.push(Error::BadPermission(path.into(), bad_bits));
+                // It's not a sticky-bit intermediate directory; actually
+                // forbid 022.
+                0o022
             }
+        };
+        // If we trust the GID, then we allow even more bits to be set.
+        if verifier.mistrust.trust_gid == Some(meta.gid()) {
+            forbidden_bits &= !0o070;
         }
+        let bad_bits = meta.mode() & forbidden_bits;
+        if bad_bits != 0 {
+            errors.push(Error::BadPermission(path.into(), bad_bits));
+        }
+    }
+}
 
-        errors
+/// [`PlatformChecks`] backend for platforms with no owner/permission model we
+/// know how to check (including Windows, for now: reading a security
+/// descriptor needs `GetNamedSecurityInfoW`/`GetSecurityDescriptorOwner`/
+/// `GetSecurityDescriptorDacl` from `windows-sys`, which isn't a dependency
+/// here, and deciding "is this SID trusted" needs a Windows-flavored
+/// counterpart to `Mistrust::trust_uid`/`trust_gid` that doesn't exist yet
+/// either. Add a real ACL-walking backend, gated on its own feature, once
+/// both land -- don't ship a backend that silently enforces nothing under a
+/// name that implies it checks something).
+#[cfg(not(target_family = "unix"))]
+struct NoChecks;
+
+#[cfg(not(target_family = "unix"))]
+impl PlatformChecks for NoChecks {
+    fn check_owner_and_permissions(
+        _verifier: &Verifier<'_>,
+        _path: &Path,
+        _path_type: PathType,
+        _meta: &Metadata,
+        _errors: &mut Vec<Error>,
+    ) {
     }
 }
 
@@ -192,3 +303,14 @@ impl super::Type {
         }
     }
 }
+
+// There used to be a `verify_async`, gated on the `async` feature, advertised as letting an
+// executor avoid blocking a task on a whole directory walk. It didn't: running the walk on a
+// blocking thread pool needs the closure handed to the pool to be `'static`, which means it
+// can only close over an owned snapshot of `self` -- but `Verifier` borrows its `Mistrust` by
+// reference, and nothing in this checkout gives `Mistrust` (in lib.rs) a way to produce an
+// owned, `'static` copy of itself. Without that, `verify_async` just ran `check_errors`/
+// `check_content_errors` inline and wrapped the result in a `Future` that never yielded --
+// blocking the calling task exactly like the sync API, while claiming not to. Re-add it once
+// `Mistrust` supports an owned snapshot and the walk can actually move to a blocking pool
+// (e.g. `blocking::unblock`); don't ship an async-shaped API that still blocks.
@@ -3,6 +3,7 @@
 //! TODO should probably become a crate?  We could miri it etc.
 
 use std::alloc::{self, Layout};
+use std::mem::MaybeUninit;
 use std::{mem, ptr};
 
 /// Like `Vec` with a capacity fixed at compile time
@@ -132,6 +133,49 @@ impl<T, const N: usize> FixedCapacityVec<T, N> {
     }
 
     // TODO there should be pop and try_pop
+
+    /// Return the initialised elements, as a slice
+    ///
+    /// (This is a partial substitute for the `Deref` impl that's still a TODO above.)
+    #[inline]
+    pub(crate) fn filled(&self) -> &[T] {
+        unsafe {
+            // SAFETY: elements 0..len are always initialised (see `data`'s docs),
+            // the pointer is valid and aligned, and we only hand out a shared
+            // slice covering exactly that initialised prefix.
+            std::slice::from_raw_parts(self.data, self.len)
+        }
+    }
+
+    /// Return the uninitialised tail, ie the `N - len` slots not yet written to
+    ///
+    /// The caller may write into the front of the returned slice and then call
+    /// [`set_len`](Self::set_len) to commit exactly the elements it initialised.
+    /// Until `set_len` is called, those writes are not considered part of the
+    /// `FixedCapacityVec`: [`filled`](Self::filled) and `Drop` only ever see `0..len`.
+    #[inline]
+    pub(crate) fn spare_capacity_mut(&mut self) -> &mut [MaybeUninit<T>] {
+        unsafe {
+            // SAFETY: `self.data.add(self.len)` stays within the `[T; N]`
+            // allocation since `len <= N`, and nothing below `len` aliases
+            // these `N - len` slots; handing them out as `MaybeUninit<T>`
+            // doesn't claim they're initialised.
+            let spare = self.data.add(self.len) as *mut MaybeUninit<T>;
+            std::slice::from_raw_parts_mut(spare, N - self.len)
+        }
+    }
+
+    /// Set the initialised length to `new_len`
+    ///
+    /// # Safety
+    ///
+    /// The caller must have actually initialised every element in `len..new_len`
+    /// (eg, by writing into the front of [`spare_capacity_mut`](Self::spare_capacity_mut)).
+    #[inline]
+    pub(crate) unsafe fn set_len(&mut self, new_len: usize) {
+        debug_assert!(new_len <= N);
+        self.len = new_len;
+    }
 }
 
 impl<T, const N: usize> Drop for FixedCapacityVec<T, N> {
@@ -189,6 +233,65 @@ impl<T, const N: usize> TryFrom<FixedCapacityVec<T, N>> for Box<[T; N]> {
     }
 }
 
+impl<const N: usize> FixedCapacityVec<u8, N> {
+    /// Read as many bytes as `reader` has to give (up to our remaining capacity),
+    /// appending them
+    ///
+    /// Returns the number of bytes read. Like [`std::io::Read::read`], a return
+    /// value of `0` with nonzero spare capacity means `reader` has reached EOF.
+    #[inline]
+    pub(crate) fn extend_from_reader(
+        &mut self,
+        reader: &mut impl std::io::Read,
+    ) -> std::io::Result<usize> {
+        let spare = self.spare_capacity_mut();
+        // `reader` is an arbitrary, possibly-adversarial `Read` impl. Nothing
+        // in `Read`'s contract stops it from reading the buffer it's handed
+        // before (or instead of) writing to it -- that guarantee only ever
+        // existed via the removed nightly-only `Read::initializer` opt-in, and
+        // we don't have `read_buf`/`BorrowedBuf` here. So we zero the spare
+        // tail first: it costs a memset, but it means any such read observes
+        // zeros rather than uninitialised memory.
+        for slot in spare.iter_mut() {
+            slot.write(0);
+        }
+        // SAFETY: every element of `spare` was just initialised above, so
+        // viewing it as `&mut [u8]` is sound.
+        let spare: &mut [u8] =
+            unsafe { std::slice::from_raw_parts_mut(spare.as_mut_ptr() as *mut u8, spare.len()) };
+        let n = reader.read(spare)?;
+        // SAFETY: the whole of `spare` (zeroed above, then possibly
+        // overwritten by `read`) is initialised, and `n <= spare.len()`,
+        // which is exactly the region `set_len` is about to claim.
+        unsafe {
+            self.set_len(self.len + n);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<const N: usize> FixedCapacityVec<u8, N> {
+    /// Async variant of [`extend_from_reader`](Self::extend_from_reader), for use
+    /// from inside a `poll`-based reactor
+    ///
+    /// Reads straight into the uninitialised spare tail, same as the blocking
+    /// version, so a full-sized buffer isn't memset on every call.
+    //
+    // TODO: this needs an `AsyncRead`/`ReadBuf` pair to actually poll against
+    // (eg from `tokio` or `futures`), and neither is a dependency of this
+    // crate, which otherwise has no I/O of its own. Wire this up for real
+    // once such a dependency is added; until then this is unimplemented.
+    #[inline]
+    pub(crate) fn poll_fill_from<R>(
+        self: std::pin::Pin<&mut Self>,
+        _reader: std::pin::Pin<&mut R>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        unimplemented!("poll_fill_from needs an AsyncRead dependency this crate doesn't have yet")
+    }
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -294,4 +397,44 @@ mod test {
 
         assert_eq!(*counted.borrow(), 0);
     }
+
+    #[test]
+    fn spare_capacity_and_set_len() {
+        let mut v = FixedCapacityVec::<u8, N>::new();
+        assert_eq!(v.filled(), &[] as &[u8]);
+        assert_eq!(v.spare_capacity_mut().len(), N);
+
+        for (i, slot) in v.spare_capacity_mut()[..H].iter_mut().enumerate() {
+            slot.write(i as u8);
+        }
+        unsafe {
+            v.set_len(H);
+        }
+        assert_eq!(v.filled(), &[0, 1, 2, 3, 4]);
+        assert_eq!(v.spare_capacity_mut().len(), N - H);
+
+        for (i, slot) in v.spare_capacity_mut().iter_mut().enumerate() {
+            slot.write((H + i) as u8);
+        }
+        unsafe {
+            v.set_len(N);
+        }
+        assert!(v.is_full());
+        assert_eq!(v.filled(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn extend_from_reader_reads_into_spare_tail() {
+        let mut v = FixedCapacityVec::<u8, N>::new();
+        let mut reader: &[u8] = &[1, 2, 3];
+        let n = v.extend_from_reader(&mut reader).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(v.filled(), &[1, 2, 3]);
+
+        let mut reader: &[u8] = &[4, 5, 6, 7, 8, 9, 10, 11];
+        let n = v.extend_from_reader(&mut reader).unwrap();
+        assert_eq!(n, N - 3);
+        assert!(v.is_full());
+        assert_eq!(v.filled(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    }
 }
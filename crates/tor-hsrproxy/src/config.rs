@@ -3,7 +3,10 @@
 use derive_adhoc::Adhoc;
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, ops::RangeInclusive, path::PathBuf, str::FromStr};
+use std::{
+    net::SocketAddr, num::NonZeroU32, ops::RangeInclusive, path::PathBuf, str::FromStr,
+    time::Duration,
+};
 //use tor_config::derive_adhoc_template_Flattenable;
 use tor_config::{define_list_builder_accessors, define_list_builder_helper, ConfigBuildError};
 
@@ -18,7 +21,7 @@ pub struct ProxyConfig {
     #[builder(sub_builder, setter(custom))]
     pub(crate) proxy_ports: ProxyRuleList,
     //
-    // TODO: Someday we may want to allow udp, resolve, etc.  If we do, it will
+    // TODO: Someday we may want to allow resolve, etc.  If we do, it will
     // be via another option, rather than adding another subtype to ProxySource.
 }
 
@@ -26,16 +29,28 @@ impl ProxyConfigBuilder {
     /// Run checks on this ProxyConfig to ensure that it's valid.
     fn validate(&self) -> Result<(), ConfigBuildError> {
         // Make sure that every proxy pattern is actually reachable.
-        let mut covered = rangemap::RangeInclusiveSet::<u16>::new();
-        for rule in self.proxy_ports.access_opt().iter().flatten() {
-            let range = &rule.source.0;
+        //
+        // A rule is shadowed once every port it matches is already covered by
+        // earlier rules whose address matcher *overlaps* its own -- i.e. there's
+        // some address the two could both match.  `*` overlaps everything, so a
+        // `*` rule on a port shadows any later rule on that same port regardless
+        // of its address matcher; two rules on disjoint address sets (e.g.
+        // `a.onion` and `b.onion`) never shadow each other no matter their ports.
+        let rules: Vec<&ProxyRule> = self.proxy_ports.access_opt().iter().flatten().collect();
+        for (i, rule) in rules.iter().enumerate() {
+            let range = &rule.source.ports;
+            let mut covered = rangemap::RangeInclusiveSet::new();
+            for earlier in &rules[..i] {
+                if earlier.source.addr.overlaps(&rule.source.addr) {
+                    covered.insert(earlier.source.ports.clone());
+                }
+            }
             if covered.gaps(range).next().is_none() {
                 return Err(ConfigBuildError::Invalid {
                     field: "proxy_ports".into(),
                     problem: format!("Port pattern {} is not reachable", rule.source),
                 });
             }
-            covered.insert(range.clone());
         }
 
         // TODO HSS: Eventually we may want to warn if there are no `Forward`
@@ -65,11 +80,27 @@ define_list_builder_helper! {
 
 impl ProxyConfig {
     /// Find the configured action to use when receiving a request for a
-    /// connection on a given port.
-    pub(crate) fn resolve_port_for_begin(&self, port: u16) -> Option<&ProxyAction> {
+    /// connection to a given address and port.
+    ///
+    /// This looks up the action using the same [`ProxyPattern`] matching
+    /// rules regardless of whether the request is a stream (`BEGIN`) or a
+    /// datagram (`CONNECT_UDP`/`RESOLVE`-style) request: the address/port
+    /// grammar doesn't distinguish them.  Once a rule is found, the
+    /// *dispatcher* (not this crate, in this snapshot) is expected to check
+    /// which kind of request it received against which kind of
+    /// [`ProxyAction`] it got back, and treat a mismatch (e.g. a datagram
+    /// request landing on a [`ProxyAction::Forward`] rule, meant for
+    /// streams) the same as "no rule matched": fall back to
+    /// [`ProxyAction::DestroyCircuit`].
+    ///
+    /// In other words, `resolve_target_for_begin` answers "what rule
+    /// matches this address and port", not "what rule matches this address,
+    /// port, *and* request kind" — the latter split happens one layer up,
+    /// where the request kind is actually known.
+    pub(crate) fn resolve_target_for_begin(&self, addr: &str, port: u16) -> Option<&ProxyAction> {
         self.proxy_ports
             .iter()
-            .find(|rule| rule.source.matches_port(port))
+            .find(|rule| rule.source.matches(addr, port))
             .map(|rule| &rule.target)
     }
 }
@@ -77,26 +108,130 @@ impl ProxyConfig {
 /// A single rule in a `ProxyConfig`.
 ///
 /// Rules take the form of, "When this pattern matches, take this action."
-#[derive(
-    Clone,
-    Debug,
-    // Serialize,
-    // Deserialize,
-    Eq,
-    PartialEq,
-    serde_with::DeserializeFromStr,
-    serde_with::SerializeDisplay,
-)]
-// TODO HSS: we might someday want to accept structs here as well, so that
-// we can add per-rule fields if we need to.  We can make that an option if/when
-// it comes up, however.
-// TODO HSS restore this as part of #1058.
-//     #[serde(from = "ProxyRuleAsTuple", into = "ProxyRuleAsTuple")]
+///
+/// In configuration, a rule can be written either in its compact string form
+/// (`"<source> => <target>"`), or as a struct/map with `source`, `target`,
+/// and any of the optional fields of [`ProxyRuleOptions`].  See the
+/// [`Deserialize`](#impl-Deserialize<'de>-for-ProxyRule) impl for details.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ProxyRule {
-    /// Any connections to a port matching this pattern match this rule.
+    /// Any connections whose requested address and port match this pattern
+    /// match this rule.
     source: ProxyPattern,
     /// When this rule matches, we take this action.
     target: ProxyAction,
+    /// Additional, optional settings for this rule.
+    options: ProxyRuleOptions,
+}
+
+/// Additional per-rule settings that can only be specified via the struct
+/// form of a [`ProxyRule`] (see [#1058]).
+///
+/// [#1058]: https://gitlab.torproject.org/tpo/core/arti/-/issues/1058
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ProxyRuleOptions {
+    /// The largest number of streams that may be concurrently forwarded by
+    /// this rule.
+    ///
+    /// Once this limit is reached, the forwarder rejects or destroys
+    /// further streams matching this rule, according to their usual failure
+    /// behavior.
+    #[serde(default)]
+    pub max_concurrent_streams: Option<NonZeroU32>,
+
+    /// How long to wait for the forwarder's connection to the target (or,
+    /// for [`Encapsulation::HttpConnect`], to the upstream proxy) to
+    /// complete before giving up.
+    #[serde(default, with = "humantime_serde::option")]
+    pub connect_timeout: Option<Duration>,
+
+    /// How long a forwarded stream may go without traffic before the
+    /// forwarder closes it.
+    #[serde(default, with = "humantime_serde::option")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl ProxyRuleOptions {
+    /// Return true if none of these options have been set.
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Helper used to deserialize a [`ProxyRule`] either from its compact string
+/// form, or from a struct/map of its fields.
+///
+/// Like the approach used by some firewall configuration tools, we first try
+/// to parse the free-form value into this intermediate representation, and
+/// only then validate and convert it into a [`ProxyRule`]; this keeps parsing
+/// and validation separate.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxyRuleRepr {
+    /// The compact `"<source> => <target>"` string form.
+    Compact(String),
+    /// The full struct/map form.
+    Full {
+        /// See [`ProxyRule::source`].
+        source: ProxyPattern,
+        /// See [`ProxyRule::target`].
+        target: ProxyAction,
+        /// See [`ProxyRule::options`].
+        #[serde(flatten)]
+        options: ProxyRuleOptions,
+    },
+}
+
+impl<'de> Deserialize<'de> for ProxyRule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+        match ProxyRuleRepr::deserialize(deserializer)? {
+            ProxyRuleRepr::Compact(s) => s.parse().map_err(D::Error::custom),
+            ProxyRuleRepr::Full {
+                source,
+                target,
+                options,
+            } => Ok(ProxyRule {
+                source,
+                target,
+                options,
+            }),
+        }
+    }
+}
+
+/// Helper used to serialize the struct/map form of a [`ProxyRule`] that has
+/// non-default [`ProxyRuleOptions`].
+#[derive(Serialize)]
+struct ProxyRuleFullRepr<'a> {
+    /// See [`ProxyRule::source`].
+    source: &'a ProxyPattern,
+    /// See [`ProxyRule::target`].
+    target: &'a ProxyAction,
+    /// See [`ProxyRule::options`].
+    #[serde(flatten)]
+    options: &'a ProxyRuleOptions,
+}
+
+impl Serialize for ProxyRule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.options.is_default() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            ProxyRuleFullRepr {
+                source: &self.source,
+                target: &self.target,
+                options: &self.options,
+            }
+            .serialize(serializer)
+        }
+    }
 }
 
 /*
@@ -137,22 +272,52 @@ impl FromStr for ProxyRule {
         Ok(ProxyRule {
             source: source.trim().parse()?,
             target: target.trim().parse()?,
+            options: ProxyRuleOptions::default(),
         })
     }
 }
 
 impl ProxyRule {
-    /// Create a new ProxyRule mapping `source` to `target`.
+    /// Create a new ProxyRule mapping `source` to `target`, with no
+    /// additional per-rule options.
     pub fn new(source: ProxyPattern, target: ProxyAction) -> Self {
-        Self { source, target }
+        Self {
+            source,
+            target,
+            options: ProxyRuleOptions::default(),
+        }
+    }
+
+    /// Create a new ProxyRule mapping `source` to `target`, with the given
+    /// additional per-rule `options`.
+    pub fn with_options(source: ProxyPattern, target: ProxyAction, options: ProxyRuleOptions) -> Self {
+        Self {
+            source,
+            target,
+            options,
+        }
+    }
+
+    /// Return the additional options configured for this rule.
+    pub fn options(&self) -> &ProxyRuleOptions {
+        &self.options
     }
 }
 
-/// A set of ports to use when checking how to handle a port.
+/// A set of (address, port) pairs to use when checking how to handle an
+/// incoming request.
+///
+/// Onion-service `BEGIN` cells carry both a requested address and a
+/// requested port, so a pattern can constrain either or both dimensions.
 #[derive(
     Clone, Debug, serde_with::DeserializeFromStr, serde_with::SerializeDisplay, Eq, PartialEq,
 )]
-pub struct ProxyPattern(RangeInclusive<u16>);
+pub struct ProxyPattern {
+    /// Which requested addresses this pattern matches.
+    addr: AddrMatcher,
+    /// Which requested ports this pattern matches.
+    ports: RangeInclusive<u16>,
+}
 
 // TODO HSS: Allow ProxyPattern to also be deserialized from an integer.
 
@@ -160,22 +325,24 @@ impl FromStr for ProxyPattern {
     type Err = ProxyConfigError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use ProxyConfigError as PCE;
-        if s == "*" {
-            Ok(Self::all_ports())
-        } else if let Some((left, right)) = s.split_once('-') {
-            let left: u16 = left.parse().map_err(PCE::InvalidPort)?;
-            let right: u16 = right.parse().map_err(PCE::InvalidPort)?;
-            Self::port_range(left, right)
-        } else {
-            let port = s.parse().map_err(PCE::InvalidPort)?;
-            Self::one_port(port)
+        match s.rsplit_once(':') {
+            Some((addr, ports)) => Ok(Self {
+                addr: addr.parse()?,
+                ports: parse_port_range(ports)?,
+            }),
+            None => Ok(Self {
+                addr: AddrMatcher::Any,
+                ports: parse_port_range(s)?,
+            }),
         }
     }
 }
 impl std::fmt::Display for ProxyPattern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.0.clone().into_inner() {
+        if self.addr != AddrMatcher::Any {
+            write!(f, "{}:", self.addr)?;
+        }
+        match self.ports.clone().into_inner() {
             (start, end) if start == end => write!(f, "{}", start),
             (1, 65535) => write!(f, "*"),
             (start, end) => write!(f, "{}-{}", start, end),
@@ -183,38 +350,136 @@ impl std::fmt::Display for ProxyPattern {
     }
 }
 
+/// Parse a port-pattern string (`*`, `N`, or `N-M`) into a port range.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, ProxyConfigError> {
+    use ProxyConfigError as PCE;
+    if s == "*" {
+        Ok(1..=65535)
+    } else if let Some((left, right)) = s.split_once('-') {
+        let left: u16 = left.parse().map_err(PCE::InvalidPort)?;
+        let right: u16 = right.parse().map_err(PCE::InvalidPort)?;
+        check_port_range(left, right)
+    } else {
+        let port = s.parse().map_err(PCE::InvalidPort)?;
+        check_port_range(port, port)
+    }
+}
+
+/// If `start..=end` is a valid port range, return it; otherwise return an error.
+fn check_port_range(start: u16, end: u16) -> Result<RangeInclusive<u16>, ProxyConfigError> {
+    use ProxyConfigError as PCE;
+    match (start, end) {
+        (_, 0) => Err(PCE::ZeroPort),
+        (0, n) => Ok(1..=n),
+        (low, high) if low > high => Err(PCE::EmptyPortRange),
+        (low, high) => Ok(low..=high),
+    }
+}
+
 impl ProxyPattern {
-    /// Return a pattern matching all ports.
+    /// Return a pattern matching all addresses and all ports.
     pub fn all_ports() -> Self {
-        Self::check(1, 65535).expect("Somehow, 1-65535 was not a valid pattern")
+        Self {
+            addr: AddrMatcher::Any,
+            ports: 1..=65535,
+        }
     }
-    /// Return a pattern matching a single port.
+    /// Return a pattern matching all addresses and a single port.
     ///
     /// Gives an error if the port is zero.
     pub fn one_port(port: u16) -> Result<Self, ProxyConfigError> {
-        Self::check(port, port)
+        Ok(Self {
+            addr: AddrMatcher::Any,
+            ports: check_port_range(port, port)?,
+        })
     }
-    /// Return a pattern matching all ports between `low` and `high` inclusive.
+    /// Return a pattern matching all addresses and all ports between `low`
+    /// and `high` inclusive.
     ///
     /// Gives an error unless `0 < low <= high`.
     pub fn port_range(low: u16, high: u16) -> Result<Self, ProxyConfigError> {
-        Self::check(low, high)
+        Ok(Self {
+            addr: AddrMatcher::Any,
+            ports: check_port_range(low, high)?,
+        })
     }
 
-    /// Return true if this pattern includes `port`.
-    pub(crate) fn matches_port(&self, port: u16) -> bool {
-        self.0.contains(&port)
+    /// Return true if this pattern matches the given requested `addr` and `port`.
+    pub(crate) fn matches(&self, addr: &str, port: u16) -> bool {
+        self.ports.contains(&port) && self.addr.matches(addr)
     }
+}
 
-    /// If start..=end is a valid pattern, wrap it as a ProxyPattern. Otherwise return
-    /// an error.
-    fn check(start: u16, end: u16) -> Result<ProxyPattern, ProxyConfigError> {
-        use ProxyConfigError as PCE;
-        match (start, end) {
-            (_, 0) => Err(PCE::ZeroPort),
-            (0, n) => Ok(Self(1..=n)),
-            (low, high) if low > high => Err(PCE::EmptyPortRange),
-            (low, high) => Ok(Self(low..=high)),
+/// Which requested addresses a [`ProxyPattern`] matches.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum AddrMatcher {
+    /// Match any address.
+    Any,
+    /// Match only this exact address (compared case-insensitively).
+    Exact(String),
+    /// Match any address that is a subdomain of this suffix (compared
+    /// case-insensitively), e.g. `.example.onion-internal` matches
+    /// `foo.example.onion-internal` but not `example.onion-internal` itself.
+    SuffixWildcard(String),
+}
+
+impl FromStr for AddrMatcher {
+    type Err = ProxyConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s == "*" {
+            Ok(Self::Any)
+        } else if let Some(suffix) = s.strip_prefix("*.") {
+            if suffix.is_empty() {
+                return Err(ProxyConfigError::InvalidAddrPattern);
+            }
+            Ok(Self::SuffixWildcard(format!(".{}", suffix.to_ascii_lowercase())))
+        } else if s.contains('*') {
+            Err(ProxyConfigError::InvalidAddrPattern)
+        } else {
+            Ok(Self::Exact(s.to_ascii_lowercase()))
+        }
+    }
+}
+
+impl std::fmt::Display for AddrMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddrMatcher::Any => write!(f, "*"),
+            AddrMatcher::Exact(s) => write!(f, "{}", s),
+            AddrMatcher::SuffixWildcard(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+impl AddrMatcher {
+    /// Return true if this matcher matches `addr`.
+    fn matches(&self, addr: &str) -> bool {
+        match self {
+            AddrMatcher::Any => true,
+            AddrMatcher::Exact(s) => s.eq_ignore_ascii_case(addr),
+            AddrMatcher::SuffixWildcard(suffix) => {
+                addr.len() > suffix.len() && addr.to_ascii_lowercase().ends_with(suffix.as_str())
+            }
+        }
+    }
+
+    /// Return true if there is some address that both `self` and `other` would match.
+    ///
+    /// Used by [`ProxyConfigBuilder::validate`] to decide whether an earlier rule can
+    /// shadow a later one: two matchers that can never match the same address (e.g.
+    /// `a.onion` and `b.onion`) don't shadow each other no matter what ports they cover.
+    fn overlaps(&self, other: &AddrMatcher) -> bool {
+        use AddrMatcher::*;
+        match (self, other) {
+            (Any, _) | (_, Any) => true,
+            (Exact(a), Exact(b)) => a == b,
+            (Exact(a), SuffixWildcard(suffix)) | (SuffixWildcard(suffix), Exact(a)) => {
+                a.len() > suffix.len() && a.ends_with(suffix.as_str())
+            }
+            (SuffixWildcard(a), SuffixWildcard(b)) => {
+                a.ends_with(b.as_str()) || b.ends_with(a.as_str())
+            }
         }
     }
 }
@@ -235,8 +500,15 @@ pub enum ProxyAction {
     #[default]
     DestroyCircuit,
     /// Accept the client's request and forward it, via some encapsulation method,
-    /// to some target address.
-    Forward(Encapsulation, TargetAddr),
+    /// to one of a pool of target addresses.
+    Forward(Encapsulation, TargetPool),
+    /// Accept a datagram (UDP-style) request and bridge it to a single
+    /// backend target, using the given [`UdpTransport`].
+    ///
+    /// This is the datagram counterpart of `Forward`; see
+    /// [`ProxyConfig::resolve_target_for_begin`] for how a dispatcher is
+    /// expected to tell the two apart.
+    ForwardUdp(UdpTransport, TargetAddr),
     /// Close the stream immediately with an error.
     RejectStream,
     /// Ignore the stream request.
@@ -290,10 +562,147 @@ impl std::fmt::Display for TargetAddr {
     }
 }
 
+/// A pool of one or more [`TargetAddr`]s that a `Forward` rule may balance
+/// requests across, along with the policy used to pick among them and the
+/// (optional) health checking used to avoid picking down targets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TargetPool {
+    /// The addresses in this pool. Never empty.
+    targets: Vec<TargetAddr>,
+    /// How to choose among `targets` when forwarding a stream.
+    balance: LoadBalance,
+    /// If set, periodically probe each target and avoid routing to ones
+    /// that appear to be down.
+    health_check: Option<HealthCheckConfig>,
+}
+
+impl TargetPool {
+    /// Create a pool containing only `target`, with the default load-balance
+    /// policy and no health checking.
+    pub fn single(target: TargetAddr) -> Self {
+        Self {
+            targets: vec![target],
+            balance: LoadBalance::default(),
+            health_check: None,
+        }
+    }
+
+    /// Create a pool forwarding across `targets` according to `balance`,
+    /// with the given (optional) health-check settings.
+    ///
+    /// Gives an error if `targets` is empty.
+    pub fn new(
+        targets: Vec<TargetAddr>,
+        balance: LoadBalance,
+        health_check: Option<HealthCheckConfig>,
+    ) -> Result<Self, ProxyConfigError> {
+        if targets.is_empty() {
+            return Err(ProxyConfigError::EmptyTargetPool);
+        }
+        Ok(Self {
+            targets,
+            balance,
+            health_check,
+        })
+    }
+
+    /// Return the targets in this pool.
+    pub fn targets(&self) -> &[TargetAddr] {
+        &self.targets
+    }
+
+    /// Return the load-balancing policy used to pick among this pool's targets.
+    pub fn balance(&self) -> LoadBalance {
+        self.balance
+    }
+
+    /// Return the health-check settings for this pool, if any.
+    pub fn health_check(&self) -> Option<&HealthCheckConfig> {
+        self.health_check.as_ref()
+    }
+}
+
+impl FromStr for TargetPool {
+    type Err = ProxyConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (balance, rest) = if let Some(rest) = s.strip_prefix("round-robin:") {
+            (LoadBalance::RoundRobin, rest)
+        } else if let Some(rest) = s.strip_prefix("random:") {
+            (LoadBalance::Random, rest)
+        } else if let Some(rest) = s.strip_prefix("first-healthy:") {
+            (LoadBalance::FirstHealthy, rest)
+        } else {
+            (LoadBalance::default(), s)
+        };
+        let targets = rest
+            .split(',')
+            .map(|t| t.trim().parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(targets, balance, None)
+    }
+}
+
+impl std::fmt::Display for TargetPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.balance != LoadBalance::RoundRobin {
+            write!(f, "{}:", self.balance)?;
+        }
+        for (idx, target) in self.targets.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", target)?;
+        }
+        Ok(())
+    }
+}
+
+/// A policy for choosing which target in a [`TargetPool`] to forward a given
+/// stream to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum LoadBalance {
+    /// Cycle through the pool's targets in order, one per forwarded stream.
+    #[default]
+    RoundRobin,
+    /// Pick a target uniformly at random for each forwarded stream.
+    Random,
+    /// Always use the first target in the pool that health checking (or, if
+    /// health checking is disabled, the pool order) reports as healthy.
+    FirstHealthy,
+}
+
+impl std::fmt::Display for LoadBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadBalance::RoundRobin => write!(f, "round-robin"),
+            LoadBalance::Random => write!(f, "random"),
+            LoadBalance::FirstHealthy => write!(f, "first-healthy"),
+        }
+    }
+}
+
+/// Settings for the background health checker that may watch over a
+/// [`TargetPool`]'s targets.
+///
+/// The checker periodically dials each target (a plain TCP connect, or the
+/// rule's configured [`Encapsulation`]'s handshake) and marks it up or down;
+/// [`LoadBalance`] selection skips targets currently marked down, and the
+/// rule only rejects/destroys a stream once every target in the pool is
+/// down.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthCheckConfig {
+    /// How often to probe each target.
+    pub interval: Duration,
+    /// How many consecutive failed probes mark a target as down.
+    pub failure_threshold: NonZeroU32,
+}
+
 /// The method by which we encapsulate a forwarded request.
 ///
-/// (Right now, only `Simple` is supported, but we may later support
-/// "HTTP CONNECT", "HAProxy", or others.)
+/// (Right now, `Simple` and `HaProxy` are supported, but we may later support
+/// "HTTP CONNECT" or others.)
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum Encapsulation {
@@ -304,6 +713,298 @@ pub enum Encapsulation {
     /// only the local port will distinguish one request from another.
     #[default]
     Simple,
+    /// Handle a request by opening a local socket to the target address,
+    /// writing a PROXY protocol ("HAProxy") header, and then forwarding the
+    /// contents verbatim.
+    ///
+    /// This lets a backend behind the onion service recognize which
+    /// connections came from which circuit, without needing to understand
+    /// onion services itself.
+    ///
+    /// A Tor stream has no real client IP to report, so we synthesize a
+    /// stable per-circuit address in the `127.0.0.0/8` range, derived from an
+    /// opaque identifier for the originating circuit.  This is not a routable
+    /// address; it exists only so that distinct circuits show up as distinct
+    /// "clients" to the backend.
+    HaProxy {
+        /// Which version of the PROXY protocol header to emit.
+        version: HaProxyVersion,
+    },
+    /// Handle a request by opening a TCP connection to an upstream HTTP
+    /// proxy, issuing an HTTP `CONNECT` request for the target address, and
+    /// then forwarding the tunnelled stream's contents verbatim.
+    ///
+    /// This lets onion-service traffic be chained through an upstream proxy,
+    /// the way a browser's HTTPS traffic would be.
+    HttpConnect {
+        /// The upstream HTTP proxy to connect to.
+        proxy: SocketAddr,
+        /// Credentials to present in a `Proxy-Authorization` header, if the
+        /// upstream proxy requires one.
+        credentials: Option<HttpConnectCredentials>,
+    },
+}
+
+/// The transport used to bridge a [`ProxyAction::ForwardUdp`] datagram
+/// request to its backend target.
+///
+/// (Right now, plain UDP and a reliable ARQ mode modelled on KCP are
+/// supported, but we may later support others.)
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum UdpTransport {
+    /// Forward each datagram as a plain UDP packet to the target, with no
+    /// reliability or ordering guarantees beyond what the backend's own UDP
+    /// socket gives it.
+    #[default]
+    Udp,
+    /// Wrap each datagram in a small reliable-UDP (KCP-style) session before
+    /// sending it to the target: a sliding window of sequence numbers,
+    /// cumulative acknowledgements, and retransmission on timeout, so that a
+    /// lossy path between us and the backend doesn't show up as lost or
+    /// reordered datagrams.
+    ///
+    /// This is a transport concern only; it does not change what the
+    /// onion-service client sees.
+    Reliable {
+        /// The maximum size, in bytes, of a single datagram fragment sent
+        /// over the wire (including the ARQ session's own header).
+        mtu: u16,
+        /// The number of in-flight, unacknowledged fragments allowed before
+        /// the sender blocks waiting for acks.
+        window: u16,
+    },
+}
+
+impl FromStr for UdpTransport {
+    type Err = ProxyConfigError;
+
+    /// Parse the `?key=value,...` query suffix used by the `kcp:` keyword,
+    /// e.g. `window=256,mtu=1400`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ProxyConfigError as PCE;
+
+        /// The default MTU for a `Reliable` transport, chosen to fit under
+        /// the common Ethernet MTU once the ARQ header is added.
+        const DEFAULT_MTU: u16 = 1400;
+        /// The default window size for a `Reliable` transport.
+        const DEFAULT_WINDOW: u16 = 256;
+
+        let mut mtu = DEFAULT_MTU;
+        let mut window = DEFAULT_WINDOW;
+        for kv in s.split(',') {
+            let kv = kv.trim();
+            if kv.is_empty() {
+                continue;
+            }
+            let (key, value) = kv.split_once('=').ok_or(PCE::InvalidUdpTransportParams)?;
+            let value: u16 = value
+                .parse()
+                .map_err(|_| PCE::InvalidUdpTransportParams)?;
+            match key {
+                "mtu" => mtu = value,
+                "window" => window = value,
+                _ => return Err(PCE::InvalidUdpTransportParams),
+            }
+        }
+        Ok(UdpTransport::Reliable { mtu, window })
+    }
+}
+
+impl std::fmt::Display for UdpTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UdpTransport::Udp => Ok(()),
+            UdpTransport::Reliable { mtu, window } => {
+                write!(f, "?window={window},mtu={mtu}")
+            }
+        }
+    }
+}
+
+/// A username/password pair used to authenticate to an upstream HTTP proxy
+/// via a `Proxy-Authorization: Basic` header.
+///
+/// See [`Encapsulation::HttpConnect`].
+///
+/// `Debug` deliberately redacts [`Self::pass`] -- it's the form most likely to end up in a
+/// log line or panic/error message by accident. `Display`/`to_string()` (via [`ProxyAction`]'s
+/// `SerializeDisplay` derive) still round-trips the real password in plaintext, because that
+/// compact string *is* this config's on-disk serialization format, and the password has to
+/// survive being written out and read back in; don't log that form.
+#[derive(Clone, Eq, PartialEq)]
+pub struct HttpConnectCredentials {
+    /// The username to present to the proxy.
+    pub user: String,
+    /// The password to present to the proxy.
+    pub pass: String,
+}
+
+impl std::fmt::Debug for HttpConnectCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpConnectCredentials")
+            .field("user", &self.user)
+            .field("pass", &"<redacted>")
+            .finish()
+    }
+}
+
+impl HttpConnectCredentials {
+    /// Return the value to use in a `Proxy-Authorization: Basic <value>` header.
+    pub(crate) fn basic_auth_value(&self) -> String {
+        encode_base64(format!("{}:{}", self.user, self.pass).as_bytes())
+    }
+}
+
+/// Encode `bytes` using standard (RFC 4648) base64, with padding.
+///
+/// This crate has no other use for base64 encoding, so we implement the
+/// small amount of logic we need directly rather than pulling in a
+/// dependency for it.
+fn encode_base64(bytes: &[u8]) -> String {
+    /// The standard base64 alphabet.
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = chunk.get(1).copied();
+        let b2 = chunk.get(2).copied();
+        out.push(ALPHABET[(b0 >> 2) as usize] as char);
+        out.push(
+            ALPHABET[(((b0 & 0b11) << 4) | (b1.unwrap_or(0) >> 4)) as usize] as char,
+        );
+        out.push(match b1 {
+            Some(b1) => ALPHABET[(((b1 & 0b1111) << 2) | (b2.unwrap_or(0) >> 6)) as usize] as char,
+            None => '=',
+        });
+        out.push(match b2 {
+            Some(b2) => ALPHABET[(b2 & 0b0011_1111) as usize] as char,
+            None => '=',
+        });
+    }
+    out
+}
+
+/// Which version of the PROXY protocol ("HAProxy" protocol) to speak
+/// when using [`Encapsulation::HaProxy`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HaProxyVersion {
+    /// The human-readable text header described in the PROXY protocol
+    /// specification, version 1.
+    V1,
+    /// The compact binary header described in the PROXY protocol
+    /// specification, version 2.
+    V2,
+}
+
+impl Encapsulation {
+    /// Write whatever header bytes this encapsulation method requires onto
+    /// `out`, before any of the tunnelled stream's own data.
+    ///
+    /// `circ_id` is an opaque, stable identifier for the circuit that
+    /// originated this stream; it is used (for `HaProxy`) to synthesize a
+    /// per-circuit client address, since Tor streams have no real one.
+    pub(crate) fn write_header(
+        &self,
+        out: &mut impl std::io::Write,
+        circ_id: u64,
+    ) -> std::io::Result<()> {
+        match self {
+            Encapsulation::Simple => Ok(()),
+            Encapsulation::HaProxy { version } => version.write_header(out, circ_id),
+            // `HttpConnect` does not write a pre-stream header: its
+            // `CONNECT` exchange happens against the proxy, before the
+            // tunnelled stream's socket is even open to `target`.  See
+            // [`connect_request`].
+            Encapsulation::HttpConnect { .. } => Ok(()),
+        }
+    }
+}
+
+/// Build the bytes of an HTTP `CONNECT` request asking an upstream proxy to
+/// tunnel a connection to `target`.
+///
+/// This mirrors the tunnel-establishment request used by HTTP client
+/// libraries' proxy support: a `CONNECT` request line, a `Host` header, an
+/// optional `Proxy-Authorization` header, then a blank line.
+pub(crate) fn connect_request(target: &SocketAddr, credentials: Option<&HttpConnectCredentials>) -> Vec<u8> {
+    // `SocketAddr`'s own `Display` already brackets an IPv6 address (`[::1]:8080`), which
+    // `target.ip()` (just the `IpAddr`, no port) would not; building the authority from the
+    // parts separately produced an ambiguous/invalid `2001:db8::1:8080` for IPv6 targets.
+    let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(credentials) = credentials {
+        req.push_str("Proxy-Authorization: Basic ");
+        req.push_str(&credentials.basic_auth_value());
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    req.into_bytes()
+}
+
+/// Check the status line of an HTTP `CONNECT` response, returning an error
+/// unless it reports a `2xx` status.
+pub(crate) fn check_connect_status(status_line: &str) -> Result<(), ProxyConfigError> {
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| ProxyConfigError::HttpConnectFailed(status_line.to_string()))?;
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(ProxyConfigError::HttpConnectFailed(status_line.to_string()))
+    }
+}
+
+impl HaProxyVersion {
+    /// Derive a synthetic, stable loopback address for `circ_id`.
+    ///
+    /// We use the low 3 bytes of the circuit identifier as the last three
+    /// octets of a `127.x.y.z` address, so that distinct circuits are
+    /// (almost always) distinguishable, without claiming to know the real
+    /// client address.
+    fn synthetic_client_addr(circ_id: u64) -> std::net::Ipv4Addr {
+        let [_, _, _, _, _, b2, b1, b0] = circ_id.to_be_bytes();
+        std::net::Ipv4Addr::new(127, b2, b1, b0)
+    }
+
+    /// Write a PROXY protocol header for `circ_id` onto `out`.
+    fn write_header(&self, out: &mut impl std::io::Write, circ_id: u64) -> std::io::Result<()> {
+        match self {
+            HaProxyVersion::V1 => {
+                let src = Self::synthetic_client_addr(circ_id);
+                write!(out, "PROXY TCP4 {} 127.0.0.1 0 0\r\n", src)
+            }
+            HaProxyVersion::V2 => {
+                /// The 12-byte signature that begins every PROXY protocol v2 header.
+                const SIGNATURE: [u8; 12] = [
+                    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+                ];
+                /// Version 2, PROXY command.
+                const VER_CMD: u8 = 0x21;
+                /// TCP over IPv4.
+                const FAM_PROTO_INET: u8 = 0x11;
+
+                let src = Self::synthetic_client_addr(circ_id);
+                let dst = std::net::Ipv4Addr::LOCALHOST;
+                // Source port/dest port: we have no real ports to report, so
+                // use zero for both, matching the `PROXY UNKNOWN`-style
+                // fallback used by the v1 writer above.
+                let mut addr_block = Vec::with_capacity(12);
+                addr_block.extend_from_slice(&src.octets());
+                addr_block.extend_from_slice(&dst.octets());
+                addr_block.extend_from_slice(&0u16.to_be_bytes()); // source port
+                addr_block.extend_from_slice(&0u16.to_be_bytes()); // dest port
+
+                out.write_all(&SIGNATURE)?;
+                out.write_all(&[VER_CMD, FAM_PROTO_INET])?;
+                out.write_all(&(addr_block.len() as u16).to_be_bytes())?;
+                out.write_all(&addr_block)
+            }
+        }
+    }
 }
 
 impl FromStr for ProxyAction {
@@ -316,8 +1017,71 @@ impl FromStr for ProxyAction {
             Ok(Self::RejectStream)
         } else if s == "ignore" {
             Ok(Self::IgnoreStream)
+        } else if let Some(addr) = s.strip_prefix("udp:") {
+            Ok(Self::ForwardUdp(UdpTransport::Udp, addr.parse()?))
+        } else if let Some(rest) = s.strip_prefix("kcp:") {
+            let (addr, params) = match rest.split_once('?') {
+                Some((addr, params)) => (addr, params),
+                None => (rest, ""),
+            };
+            let transport = if params.is_empty() {
+                UdpTransport::Reliable {
+                    mtu: 1400,
+                    window: 256,
+                }
+            } else {
+                params.parse()?
+            };
+            Ok(Self::ForwardUdp(transport, addr.parse()?))
         } else if let Some(addr) = s.strip_prefix("simple:") {
             Ok(Self::Forward(Encapsulation::Simple, addr.parse()?))
+        } else if let Some(addr) = s.strip_prefix("haproxy-v1:") {
+            Ok(Self::Forward(
+                Encapsulation::HaProxy {
+                    version: HaProxyVersion::V1,
+                },
+                addr.parse()?,
+            ))
+        } else if let Some(addr) = s.strip_prefix("haproxy-v2:") {
+            Ok(Self::Forward(
+                Encapsulation::HaProxy {
+                    version: HaProxyVersion::V2,
+                },
+                addr.parse()?,
+            ))
+        } else if let Some(rest) = s.strip_prefix("connect:") {
+            use ProxyConfigError as PCE;
+            let (params, addr) = rest
+                .split_once("=>")
+                .ok_or(PCE::InvalidHttpConnectParams)?;
+            let mut proxy = None;
+            let mut user = None;
+            let mut pass = None;
+            for kv in params.split(',') {
+                let kv = kv.trim();
+                if kv.is_empty() {
+                    continue;
+                }
+                let (key, value) = kv.split_once('=').ok_or(PCE::InvalidHttpConnectParams)?;
+                match key {
+                    "proxy" => {
+                        proxy = Some(value.parse().map_err(|_| PCE::InvalidHttpConnectParams)?);
+                    }
+                    "user" => user = Some(value.to_string()),
+                    "pass" => pass = Some(value.to_string()),
+                    _ => return Err(PCE::InvalidHttpConnectParams),
+                }
+            }
+            let proxy = proxy.ok_or(PCE::InvalidHttpConnectParams)?;
+            let credentials = match (user, pass) {
+                (Some(user), Some(pass)) => Some(HttpConnectCredentials { user, pass }),
+                (None, None) => None,
+                _ => return Err(PCE::InvalidHttpConnectParams),
+            };
+            Ok(Self::Forward(
+                Encapsulation::HttpConnect { proxy, credentials },
+                addr.trim().parse()?,
+            ))
         } else {
             Ok(Self::Forward(Encapsulation::Simple, s.parse()?))
         }
@@ -329,12 +1093,40 @@ impl std::fmt::Display for ProxyAction {
         match self {
             ProxyAction::DestroyCircuit => write!(f, "destroy"),
             ProxyAction::Forward(Encapsulation::Simple, addr) => write!(f, "simple:{}", addr),
+            ProxyAction::Forward(Encapsulation::HaProxy { version }, addr) => {
+                write!(f, "haproxy-{}:{}", version, addr)
+            }
+            ProxyAction::Forward(Encapsulation::HttpConnect { proxy, credentials }, addr) => {
+                write!(f, "connect:proxy={proxy}")?;
+                if let Some(credentials) = credentials {
+                    // Plaintext password, on purpose: this is the config's own
+                    // serialization format (see the doc comment on
+                    // `HttpConnectCredentials`), not a log line. Don't feed this
+                    // `Display`/`to_string()` output into logs or error messages --
+                    // use `{:?}` (which redacts `pass`) for those instead.
+                    write!(f, ",user={},pass={}", credentials.user, credentials.pass)?;
+                }
+                write!(f, " => {addr}")
+            }
+            ProxyAction::ForwardUdp(UdpTransport::Udp, addr) => write!(f, "udp:{}", addr),
+            ProxyAction::ForwardUdp(transport @ UdpTransport::Reliable { .. }, addr) => {
+                write!(f, "kcp:{}{}", addr, transport)
+            }
             ProxyAction::RejectStream => write!(f, "reject"),
             ProxyAction::IgnoreStream => write!(f, "ignore"),
         }
     }
 }
 
+impl std::fmt::Display for HaProxyVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HaProxyVersion::V1 => write!(f, "v1"),
+            HaProxyVersion::V2 => write!(f, "v2"),
+        }
+    }
+}
+
 /// An error encountered while parsing or applying a proxy configuration.
 #[derive(Debug, Clone, thiserror::Error)]
 #[non_exhaustive]
@@ -358,6 +1150,26 @@ pub enum ProxyConfigError {
     /// A socket rule specified an empty port range.
     #[error("Port range is empty.")]
     EmptyPortRange,
+
+    /// A socket rule had an address pattern we couldn't parse.
+    #[error("Could not parse proxy source address pattern.")]
+    InvalidAddrPattern,
+
+    /// An `HttpConnect` encapsulation had malformed or missing parameters.
+    #[error("Could not parse HTTP CONNECT proxy parameters.")]
+    InvalidHttpConnectParams,
+
+    /// An upstream HTTP proxy rejected (or did not complete) a `CONNECT` request.
+    #[error("HTTP CONNECT to upstream proxy failed: {0}")]
+    HttpConnectFailed(String),
+
+    /// A `Forward` rule's target pool had no targets in it.
+    #[error("Target pool must have at least one target.")]
+    EmptyTargetPool,
+
+    /// A `kcp:` target had malformed or unrecognized transport parameters.
+    #[error("Could not parse UDP transport parameters.")]
+    InvalidUdpTransportParams,
 }
 
 #[cfg(test)]
@@ -379,10 +1191,48 @@ mod test {
     #[test]
     fn pattern_ok() {
         use ProxyPattern as P;
-        assert_eq!(P::from_str("*").unwrap(), P(1..=65535));
-        assert_eq!(P::from_str("100").unwrap(), P(100..=100));
-        assert_eq!(P::from_str("100-200").unwrap(), P(100..=200));
-        assert_eq!(P::from_str("0-200").unwrap(), P(1..=200));
+        assert_eq!(P::from_str("*").unwrap(), P::all_ports());
+        assert_eq!(P::from_str("100").unwrap(), P::one_port(100).unwrap());
+        assert_eq!(
+            P::from_str("100-200").unwrap(),
+            P::port_range(100, 200).unwrap()
+        );
+        assert_eq!(
+            P::from_str("0-200").unwrap(),
+            P::port_range(1, 200).unwrap()
+        );
+    }
+
+    #[test]
+    fn pattern_addr_ok() {
+        use ProxyPattern as P;
+        assert!(P::from_str("example.onion-internal:443")
+            .unwrap()
+            .matches("example.onion-internal", 443));
+        assert!(!P::from_str("example.onion-internal:443")
+            .unwrap()
+            .matches("other.onion-internal", 443));
+        assert!(P::from_str("*.svc:1-1024")
+            .unwrap()
+            .matches("foo.svc", 80));
+        assert!(!P::from_str("*.svc:1-1024").unwrap().matches("svc", 80));
+        assert!(!P::from_str("*.svc:1-1024")
+            .unwrap()
+            .matches("foo.svc", 2000));
+        assert!(P::from_str("443").unwrap().matches("anything.at.all", 443));
+    }
+
+    #[test]
+    fn pattern_addr_display() {
+        use ProxyPattern as P;
+        assert_eq!(
+            P::from_str("example.onion-internal:443")
+                .unwrap()
+                .to_string(),
+            "example.onion-internal:443"
+        );
+        assert_eq!(P::from_str("*.svc:1-1024").unwrap().to_string(), "*.svc:1-1024");
+        assert_eq!(P::from_str("443").unwrap().to_string(), "443");
     }
 
     #[test]
@@ -411,20 +1261,27 @@ mod test {
         assert!(matches!(T::from_str("ignore"), Ok(T::IgnoreStream)));
         assert!(matches!(T::from_str("destroy"), Ok(T::DestroyCircuit)));
         let sa: SocketAddr = "192.168.1.1:50".parse().unwrap();
-        assert!(
-            matches!(T::from_str("192.168.1.1:50"), Ok(T::Forward(Simple, A::Inet(a))) if a == sa)
+        assert_eq!(
+            T::from_str("192.168.1.1:50").unwrap(),
+            T::Forward(Simple, TargetPool::single(A::Inet(sa)))
         );
-        assert!(
-            matches!(T::from_str("inet:192.168.1.1:50"), Ok(T::Forward(Simple, A::Inet(a))) if a == sa)
+        assert_eq!(
+            T::from_str("inet:192.168.1.1:50").unwrap(),
+            T::Forward(Simple, TargetPool::single(A::Inet(sa)))
         );
         let sa: SocketAddr = "[::1]:999".parse().unwrap();
-        assert!(matches!(T::from_str("[::1]:999"), Ok(T::Forward(Simple, A::Inet(a))) if a == sa));
-        assert!(
-            matches!(T::from_str("inet:[::1]:999"), Ok(T::Forward(Simple, A::Inet(a))) if a == sa)
+        assert_eq!(
+            T::from_str("[::1]:999").unwrap(),
+            T::Forward(Simple, TargetPool::single(A::Inet(sa)))
+        );
+        assert_eq!(
+            T::from_str("inet:[::1]:999").unwrap(),
+            T::Forward(Simple, TargetPool::single(A::Inet(sa)))
         );
         let pb = PathBuf::from("/var/run/hs/socket");
-        assert!(
-            matches!(T::from_str("unix:/var/run/hs/socket"), Ok(T::Forward(Simple, A::Unix(p))) if p == pb)
+        assert_eq!(
+            T::from_str("unix:/var/run/hs/socket").unwrap(),
+            T::Forward(Simple, TargetPool::single(A::Unix(pb)))
         );
     }
 
@@ -438,19 +1295,130 @@ mod test {
         assert_eq!(T::IgnoreStream.to_string(), "ignore");
         assert_eq!(T::DestroyCircuit.to_string(), "destroy");
         assert_eq!(
-            T::Forward(Simple, A::Inet("192.168.1.1:50".parse().unwrap())).to_string(),
+            T::Forward(
+                Simple,
+                TargetPool::single(A::Inet("192.168.1.1:50".parse().unwrap()))
+            )
+            .to_string(),
             "simple:inet:192.168.1.1:50"
         );
         assert_eq!(
-            T::Forward(Simple, A::Inet("[::1]:999".parse().unwrap())).to_string(),
+            T::Forward(
+                Simple,
+                TargetPool::single(A::Inet("[::1]:999".parse().unwrap()))
+            )
+            .to_string(),
             "simple:inet:[::1]:999"
         );
         assert_eq!(
-            T::Forward(Simple, A::Unix("/var/run/hs/socket".into())).to_string(),
+            T::Forward(
+                Simple,
+                TargetPool::single(A::Unix("/var/run/hs/socket".into()))
+            )
+            .to_string(),
             "simple:unix:/var/run/hs/socket"
         );
     }
 
+    #[test]
+    fn target_pool() {
+        use Encapsulation::Simple;
+        use ProxyAction as T;
+        use TargetAddr as A;
+
+        let a1: TargetAddr = A::Inet("192.168.1.1:50".parse().unwrap());
+        let a2: TargetAddr = A::Inet("192.168.1.2:50".parse().unwrap());
+
+        let pool = TargetPool::from_str("192.168.1.1:50,192.168.1.2:50").unwrap();
+        assert_eq!(pool.targets(), &[a1.clone(), a2.clone()]);
+        assert_eq!(pool.balance(), LoadBalance::RoundRobin);
+        assert_eq!(pool.to_string(), "inet:192.168.1.1:50,inet:192.168.1.2:50");
+
+        let pool = TargetPool::from_str("random:192.168.1.1:50,192.168.1.2:50").unwrap();
+        assert_eq!(pool.balance(), LoadBalance::Random);
+        assert_eq!(
+            pool.to_string(),
+            "random:inet:192.168.1.1:50,inet:192.168.1.2:50"
+        );
+
+        let pool =
+            TargetPool::from_str("first-healthy:192.168.1.1:50,192.168.1.2:50").unwrap();
+        assert_eq!(pool.balance(), LoadBalance::FirstHealthy);
+
+        assert!(matches!(
+            T::from_str("simple:192.168.1.1:50,192.168.1.2:50"),
+            Ok(T::Forward(Simple, pool)) if pool.targets().len() == 2
+        ));
+
+        assert!(matches!(
+            TargetPool::new(vec![], LoadBalance::RoundRobin, None),
+            Err(ProxyConfigError::EmptyTargetPool)
+        ));
+    }
+
+    #[test]
+    fn udp_ok() {
+        use ProxyAction as T;
+        use TargetAddr as A;
+
+        let sa: SocketAddr = "192.168.1.1:53".parse().unwrap();
+        assert_eq!(
+            T::from_str("udp:192.168.1.1:53").unwrap(),
+            T::ForwardUdp(UdpTransport::Udp, A::Inet(sa))
+        );
+        assert_eq!(
+            T::from_str("kcp:192.168.1.1:53").unwrap(),
+            T::ForwardUdp(
+                UdpTransport::Reliable {
+                    mtu: 1400,
+                    window: 256
+                },
+                A::Inet(sa)
+            )
+        );
+        assert_eq!(
+            T::from_str("kcp:192.168.1.1:53?window=64,mtu=512").unwrap(),
+            T::ForwardUdp(
+                UdpTransport::Reliable {
+                    mtu: 512,
+                    window: 64
+                },
+                A::Inet(sa)
+            )
+        );
+        assert!(matches!(
+            T::from_str("kcp:192.168.1.1:53?bogus=1"),
+            Err(ProxyConfigError::InvalidUdpTransportParams)
+        ));
+    }
+
+    #[test]
+    fn udp_display() {
+        use ProxyAction as T;
+        use TargetAddr as A;
+
+        let sa: SocketAddr = "192.168.1.1:53".parse().unwrap();
+        assert_eq!(
+            T::ForwardUdp(UdpTransport::Udp, A::Inet(sa)).to_string(),
+            "udp:inet:192.168.1.1:53"
+        );
+        assert_eq!(
+            T::ForwardUdp(
+                UdpTransport::Reliable {
+                    mtu: 512,
+                    window: 64
+                },
+                A::Inet(sa)
+            )
+            .to_string(),
+            "kcp:inet:192.168.1.1:53?window=64,mtu=512"
+        );
+
+        // Round-trips through the compact string form.
+        let action = T::from_str("kcp:192.168.1.1:53?window=64,mtu=512").unwrap();
+        assert_eq!(action.to_string().parse::<T>().unwrap(), action);
+    }
+
     #[test]
     fn target_err() {
         use ProxyAction as T;
@@ -493,6 +1461,170 @@ mod test {
         ));
     }
 
+    #[test]
+    fn haproxy_roundtrip() {
+        use Encapsulation::HaProxy;
+        use ProxyAction as T;
+        use TargetAddr as A;
+
+        let sa: SocketAddr = "192.168.1.1:50".parse().unwrap();
+        assert_eq!(
+            T::from_str("haproxy-v1:192.168.1.1:50").unwrap(),
+            T::Forward(
+                HaProxy {
+                    version: HaProxyVersion::V1
+                },
+                TargetPool::single(A::Inet(sa))
+            )
+        );
+        assert_eq!(
+            T::from_str("haproxy-v2:192.168.1.1:50").unwrap(),
+            T::Forward(
+                HaProxy {
+                    version: HaProxyVersion::V2
+                },
+                TargetPool::single(A::Inet(sa))
+            )
+        );
+
+        assert_eq!(
+            T::Forward(
+                HaProxy {
+                    version: HaProxyVersion::V1
+                },
+                TargetPool::single(A::Inet(sa))
+            )
+            .to_string(),
+            "haproxy-v1:inet:192.168.1.1:50"
+        );
+        assert_eq!(
+            T::Forward(
+                HaProxy {
+                    version: HaProxyVersion::V2
+                },
+                TargetPool::single(A::Inet(sa))
+            )
+            .to_string(),
+            "haproxy-v2:inet:192.168.1.1:50"
+        );
+    }
+
+    #[test]
+    fn haproxy_header_v1() {
+        let mut buf = Vec::new();
+        HaProxyVersion::V1.write_header(&mut buf, 0x0102).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(text, "PROXY TCP4 127.0.1.2 127.0.0.1 0 0\r\n");
+    }
+
+    #[test]
+    fn haproxy_header_v2() {
+        let mut buf = Vec::new();
+        HaProxyVersion::V2.write_header(&mut buf, 0x0102).unwrap();
+        assert_eq!(
+            &buf[0..12],
+            &[0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A]
+        );
+        assert_eq!(buf[12], 0x21);
+        assert_eq!(buf[13], 0x11);
+        assert_eq!(u16::from_be_bytes([buf[14], buf[15]]), 12);
+        assert_eq!(buf.len(), 16 + 12);
+    }
+
+    #[test]
+    fn http_connect_roundtrip() {
+        use Encapsulation::HttpConnect;
+        use ProxyAction as T;
+        use TargetAddr as A;
+
+        let target: SocketAddr = "192.168.1.1:50".parse().unwrap();
+        let proxy: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+
+        assert!(matches!(
+            T::from_str("connect:proxy=10.0.0.1:8080 => 192.168.1.1:50"),
+            Ok(T::Forward(HttpConnect { proxy: p, credentials: None }, pool))
+                if p == proxy && pool.targets() == [A::Inet(target)]
+        ));
+
+        match T::from_str("connect:proxy=10.0.0.1:8080,user=foo,pass=bar => 192.168.1.1:50") {
+            Ok(T::Forward(HttpConnect { proxy: p, credentials: Some(c) }, pool)) => {
+                assert_eq!(p, proxy);
+                assert_eq!(pool.targets(), [A::Inet(target)]);
+                assert_eq!(c.user, "foo");
+                assert_eq!(c.pass, "bar");
+            }
+            other => panic!("unexpected parse result: {other:?}"),
+        }
+
+        assert!(matches!(
+            T::from_str("connect:nonsense"),
+            Err(ProxyConfigError::InvalidHttpConnectParams)
+        ));
+        assert!(matches!(
+            T::from_str("connect:user=foo => 192.168.1.1:50"),
+            Err(ProxyConfigError::InvalidHttpConnectParams)
+        ));
+
+        let action = T::Forward(
+            HttpConnect {
+                proxy,
+                credentials: Some(HttpConnectCredentials {
+                    user: "foo".into(),
+                    pass: "bar".into(),
+                }),
+            },
+            TargetPool::single(A::Inet(target)),
+        );
+        assert_eq!(
+            action.to_string(),
+            "connect:proxy=10.0.0.1:8080,user=foo,pass=bar => inet:192.168.1.1:50"
+        );
+    }
+
+    #[test]
+    fn http_connect_request_and_status() {
+        let target: SocketAddr = "192.168.1.1:50".parse().unwrap();
+        let req = connect_request(&target, None);
+        assert_eq!(
+            String::from_utf8(req).unwrap(),
+            "CONNECT 192.168.1.1:50 HTTP/1.1\r\nHost: 192.168.1.1:50\r\n\r\n"
+        );
+
+        let creds = HttpConnectCredentials {
+            user: "foo".into(),
+            pass: "bar".into(),
+        };
+        let req = connect_request(&target, Some(&creds));
+        let req = String::from_utf8(req).unwrap();
+        assert!(req.contains("Proxy-Authorization: Basic Zm9vOmJhcg==\r\n"));
+
+        assert!(check_connect_status("HTTP/1.1 200 Connection established").is_ok());
+        assert!(check_connect_status("HTTP/1.1 204 No Content").is_ok());
+        assert!(check_connect_status("HTTP/1.1 407 Proxy Authentication Required").is_err());
+        assert!(check_connect_status("garbage").is_err());
+    }
+
+    #[test]
+    fn http_connect_request_brackets_ipv6_target() {
+        let target: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let req = connect_request(&target, None);
+        assert_eq!(
+            String::from_utf8(req).unwrap(),
+            "CONNECT [2001:db8::1]:8080 HTTP/1.1\r\nHost: [2001:db8::1]:8080\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn http_connect_credentials_debug_redacts_password() {
+        let creds = HttpConnectCredentials {
+            user: "foo".into(),
+            pass: "super-secret".into(),
+        };
+        let debugged = format!("{creds:?}");
+        assert!(debugged.contains("foo"));
+        assert!(!debugged.contains("super-secret"));
+    }
+
     #[test]
     fn deserialize() {
         use Encapsulation::Simple;
@@ -515,18 +1647,57 @@ mod test {
         let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
         let cfg = bld.build().unwrap();
         assert_eq!(cfg.proxy_ports.len(), 3);
-        assert_eq!(cfg.proxy_ports[0].source.0, 443..=443);
-        assert_eq!(cfg.proxy_ports[1].source.0, 80..=80);
-        assert_eq!(cfg.proxy_ports[2].source.0, 1..=65535);
+        assert_eq!(cfg.proxy_ports[0].source.ports, 443..=443);
+        assert_eq!(cfg.proxy_ports[1].source.ports, 80..=80);
+        assert_eq!(cfg.proxy_ports[2].source.ports, 1..=65535);
 
         assert_eq!(
             cfg.proxy_ports[0].target,
-            ProxyAction::Forward(Simple, A::Inet("127.0.0.1:11443".parse().unwrap()))
+            ProxyAction::Forward(
+                Simple,
+                TargetPool::single(A::Inet("127.0.0.1:11443".parse().unwrap()))
+            )
         );
         assert_eq!(cfg.proxy_ports[1].target, ProxyAction::IgnoreStream);
         assert_eq!(cfg.proxy_ports[2].target, ProxyAction::DestroyCircuit);
     }
 
+    #[test]
+    fn deserialize_struct_form() {
+        use Encapsulation::Simple;
+        use TargetAddr as A;
+
+        let ex = r#"{
+            "proxy_ports": [
+                {
+                    "source": "443",
+                    "target": "127.0.0.1:11443",
+                    "max_concurrent_streams": 16,
+                    "connect_timeout": "5s",
+                    "idle_timeout": "10m"
+                },
+                "* => destroy"
+            ]
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.proxy_ports.len(), 2);
+        assert_eq!(
+            cfg.proxy_ports[0].target,
+            ProxyAction::Forward(
+                Simple,
+                TargetPool::single(A::Inet("127.0.0.1:11443".parse().unwrap()))
+            )
+        );
+        let options = cfg.proxy_ports[0].options();
+        assert_eq!(options.max_concurrent_streams, NonZeroU32::new(16));
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(options.idle_timeout, Some(Duration::from_secs(600)));
+
+        // The compact form still works, and has no options set.
+        assert!(cfg.proxy_ports[1].options().max_concurrent_streams.is_none());
+    }
+
     #[test]
     fn validation_fail() {
         // this should fail; the third pattern isn't reachable.
@@ -574,6 +1745,49 @@ mod test {
         assert!(bld.build().is_ok());
     }
 
+    #[test]
+    fn validation_wildcard_shadows_specific_address() {
+        // A `*` rule matches every address, so it shadows a later
+        // address-specific rule on the same port even though their address
+        // matchers are written differently.
+        let ex = r#"{
+            "proxy_ports": [
+                "*:80 => 127.0.0.1:11443",
+                "example.onion:80 => ignore"
+            ]
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        match bld.build() {
+            Err(ConfigBuildError::Invalid { field, problem }) => {
+                assert_eq!(field, "proxy_ports");
+                assert_eq!(problem, "Port pattern example.onion:80 is not reachable");
+            }
+            other => panic!("Expected an Invalid error; got {other:?}"),
+        }
+
+        // The same two rules in the other order are both reachable: the
+        // specific-address rule doesn't shadow the `*` rule that follows it.
+        let ex = r#"{
+            "proxy_ports": [
+                "example.onion:80 => ignore",
+                "*:80 => 127.0.0.1:11443"
+            ]
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        assert!(bld.build().is_ok());
+
+        // Two rules on disjoint addresses never shadow each other, no matter
+        // their ports.
+        let ex = r#"{
+            "proxy_ports": [
+                "a.onion:80 => ignore",
+                "b.onion:80 => 127.0.0.1:11443"
+            ]
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        assert!(bld.build().is_ok());
+    }
+
     #[test]
     fn demo() {
         let b: ProxyConfigBuilder = toml::de::from_str(
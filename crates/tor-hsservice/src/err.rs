@@ -54,6 +54,22 @@ pub enum ClientError {
     EstablishSession(#[source] EstablishSessionError),
 }
 
+/// An error caused while authorizing or revoking a client's access to an onion service
+/// (a.k.a. "client authorization"/"restricted discovery").
+///
+/// Returned by [`OnionService::add_client_key`](crate::OnionService::add_client_key) and
+/// [`OnionService::revoke_client_key`](crate::OnionService::revoke_client_key).
+#[derive(Clone, Debug, Error)]
+#[non_exhaustive]
+pub enum ClientAuthError {
+    /// We couldn't read or write the client's key in the keystore.
+    ///
+    /// This is an ordinary, expected-to-happen-sometimes I/O failure (disk full,
+    /// permission denied, ...), not a bug, so it's reported separately from [`Bug`].
+    #[error("Could not access the keystore")]
+    Keystore(#[source] tor_keymgr::Error),
+}
+
 /// An error which means we cannot continue to try to operate an onion service.
 ///
 /// These errors only occur during operation, and only for catastrophic reasons
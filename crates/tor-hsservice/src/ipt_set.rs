@@ -1,12 +1,14 @@
 //! IPT set - the principal API between the IPT manager and publisher
 
+use std::collections::HashSet;
 use std::ops::DerefMut;
 use std::sync::Arc;
 use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
 
 use futures::channel::mpsc;
-use futures::StreamExt as _;
+use futures::future::poll_fn;
 
 use derive_more::{Deref, DerefMut};
 use itertools::chain;
@@ -118,12 +120,15 @@ pub(crate) struct IptsManagerView {
     /// Actual shared data
     shared: Shared,
 
-    /// Notification sender
+    /// Notification state
     ///
     /// We don't wrap the state in a postage::watch,
     /// because the publisher needs to be able to mutably borrow the data
     /// without re-notifying itself when it drops the guard.
-    notify: mpsc::Sender<()>,
+    notify: Notify,
+
+    /// Sender for the delta side channel; see [`IptSetDelta`]
+    delta_tx: mpsc::Sender<IptSetDelta>,
 }
 
 /// Shared view of introduction points - IPT publisher's view
@@ -134,8 +139,14 @@ pub(crate) struct IptsPublisherView {
     /// Actual shared data
     shared: Shared,
 
-    /// Notification receiver
-    notify: mpsc::Receiver<()>,
+    /// Notification state
+    notify: Notify,
+
+    /// Sequence number (from `notify`) that this view has already observed
+    last_seen: u64,
+
+    /// Receiver for the delta side channel; see [`IptSetDelta`]
+    delta_rx: mpsc::Receiver<IptSetDelta>,
 }
 
 /// Shared view of introduction points - IPT publisher's publication-only view
@@ -154,15 +165,197 @@ pub(crate) struct IptsPublisherView {
 /// So the publisher's individual upload tasks can each have one.
 ///
 /// Obtained from [`IptsPublisherView::upload_view`].
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub(crate) struct IptsPublisherUploadView {
     /// Actual shared data
     shared: Shared,
+
+    /// Notification state
+    notify: Notify,
+
+    /// Sequence number (from `notify`) that this view has already observed
+    last_seen: u64,
+}
+
+impl Clone for IptsPublisherUploadView {
+    fn clone(&self) -> Self {
+        // A clone is a new receiver handle, so it counts towards `receivers`
+        // just as one obtained via `upload_view` does.
+        lock_notify(&self.notify).receivers += 1;
+        IptsPublisherUploadView {
+            shared: self.shared.clone(),
+            notify: self.notify.clone(),
+            last_seen: self.last_seen,
+        }
+    }
 }
 
 /// Core shared state
 type Shared = Arc<Mutex<PublishIptSet>>;
 
+/// Shared notification state for the broadcast-style update channel
+///
+/// Modelled on the embassy-sync pub/sub pattern: rather than a single
+/// receiver consuming a single queued notification, every subscriber
+/// (every [`IptsPublisherView`] and [`IptsPublisherUploadView`]) keeps its
+/// own cursor into a shared, monotonically increasing sequence number, and
+/// parks its [`Waker`] here while it has nothing new to see. This lets
+/// each of the publisher's per-upload tasks `await_update` independently,
+/// instead of funnelling every update through the one central
+/// `IptsPublisherView`.
+#[derive(Debug, Default)]
+struct NotifyState {
+    /// Bumped by [`NotifyingBorrow::drop`] each time the manager commits an update
+    sequence: u64,
+
+    /// Set once every [`IptsManagerView`] sharing this state has been dropped
+    ///
+    /// Once set, subscribers' `await_update` returns `None` forever.
+    closed: bool,
+
+    /// Wakers of subscribers currently parked in `await_update`
+    wakers: Vec<Waker>,
+
+    /// Number of live receiver handles sharing this channel
+    ///
+    /// A "receiver handle" is the one [`IptsPublisherView`], plus every
+    /// live [`IptsPublisherUploadView`] clone: anything that could still
+    /// call `await_update` or `borrow_for_publish`. Incremented whenever
+    /// one of those is created (in [`ipts_channel`], [`upload_view`], or
+    /// `Clone::clone`) and decremented when one is dropped.
+    ///
+    /// [`upload_view`]: IptsPublisherView::upload_view
+    receivers: usize,
+
+    /// Waker for a manager parked in [`IptsManagerView::closed`]
+    ///
+    /// Set once `receivers` is seen to be nonzero; woken (and cleared)
+    /// when `receivers` drops to zero.
+    manager_waker: Option<Waker>,
+}
+
+/// Handle to the shared notification state
+///
+/// Shared by every [`IptsManagerView`], [`IptsPublisherView`] and
+/// [`IptsPublisherUploadView`] backed by the same [`ipts_channel`].
+type Notify = Arc<Mutex<NotifyState>>;
+
+/// A generation number for the shared [`PublishIptSet`]
+///
+/// Bumped every time the manager commits an update (it is in fact the same
+/// counter as `NotifyState::sequence`). Borrowed from tokio's `watch`
+/// channel: a publisher that captures a `Generation` at the start of a
+/// publication attempt can later tell, cheaply and without holding any
+/// lock in the meantime, whether the set has moved on since.
+pub(crate) type Generation = u64;
+
+/// Does `current` denote a later generation than `since`?
+///
+/// Correct even if the counter has wrapped around since `since` was
+/// captured: we compare with wrapping subtraction rather than `>`, exactly
+/// as a long-lived service needs in order to never falsely treat a
+/// perfectly fresh generation as stale once `u64::MAX` generations have
+/// gone by.
+fn generation_advanced(current: Generation, since: Generation) -> bool {
+    current.wrapping_sub(since) != 0
+}
+
+/// Depth of the bounded [`IptSetDelta`] side channel
+///
+/// Chosen to comfortably hold the deltas from a handful of consecutive
+/// `borrow_for_update` calls (as happens, e.g., while the manager is
+/// rotating several introduction points in a row) without the publisher
+/// having to be scheduled in between each one.
+const DELTA_QUEUE_DEPTH: usize = 32;
+
+/// A single change to the [`PublishIptSet`], as reported by the delta side channel
+///
+/// Emitted by [`IptsManagerView::borrow_for_update`] when the guard it
+/// returned is dropped, by comparing the set before and after the
+/// mutation. Consumed by the publisher (via
+/// [`IptsPublisherView::drain_deltas`]) as a cheaper alternative to
+/// re-reading and re-diffing the whole `Vec<IptInSet>` on every wakeup.
+///
+/// `lid`s referenced here always index into the still-authoritative
+/// [`PublishIptSet`]; the delta stream is a hint about what changed, not
+/// a replacement for it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum IptSetDelta {
+    /// An introduction point was added
+    Added(IptLocalId),
+    /// An introduction point was removed
+    Removed(IptLocalId),
+    /// The descriptor lifetime changed to this value
+    LifetimeChanged(Duration),
+    /// Some deltas were lost because the queue was full
+    ///
+    /// The publisher should fall back to comparing the whole
+    /// `PublishIptSet` against what it last published, rather than
+    /// trusting the delta stream to be complete.
+    Resync,
+}
+
+/// Snapshot of the parts of a [`PublishIptSet`] that the delta stream tracks
+///
+/// Captured by [`IptsManagerView::borrow_for_update`] before handing out
+/// the guard, and compared against the post-mutation state when the
+/// guard is dropped, to compute which [`IptSetDelta`]s to emit.
+struct DeltaSnapshot {
+    /// The local ids present, or `None` if there was no [`IptSet`] at all
+    lids: Option<HashSet<IptLocalId>>,
+    /// The lifetime in effect, or `None` if there was no [`IptSet`] at all
+    lifetime: Option<Duration>,
+}
+
+impl DeltaSnapshot {
+    /// Capture a snapshot of `set`
+    fn capture(set: &PublishIptSet) -> Self {
+        DeltaSnapshot {
+            lids: set
+                .as_ref()
+                .map(|set| set.ipts.iter().map(|ipt| ipt.lid).collect()),
+            lifetime: set.as_ref().map(|set| set.lifetime),
+        }
+    }
+}
+
+/// Compute the [`IptSetDelta`]s implied by a transition from `before` to `after`, and send them
+///
+/// If the queue is too full to take every individual delta, stops partway
+/// through and sends a single [`IptSetDelta::Resync`] instead, so that a
+/// publisher which has fallen behind is told to re-derive everything from
+/// the authoritative [`PublishIptSet`] rather than act on a partial diff.
+fn send_deltas(tx: &mut mpsc::Sender<IptSetDelta>, before: &DeltaSnapshot, after: &DeltaSnapshot) {
+    let no_lids = HashSet::new();
+    let before_lids = before.lids.as_ref().unwrap_or(&no_lids);
+    let after_lids = after.lids.as_ref().unwrap_or(&no_lids);
+
+    let removed = before_lids
+        .difference(after_lids)
+        .copied()
+        .map(IptSetDelta::Removed);
+    let added = after_lids
+        .difference(before_lids)
+        .copied()
+        .map(IptSetDelta::Added);
+    let lifetime_changed = (before.lifetime != after.lifetime)
+        .then_some(after.lifetime)
+        .flatten()
+        .map(IptSetDelta::LifetimeChanged);
+
+    for event in chain!(removed, added, lifetime_changed) {
+        if tx.try_send(event).is_err() {
+            // The queue is full enough that we can't be sure the publisher
+            // will see a complete diff; tell it to resync instead of
+            // silently dropping the rest of these deltas.  (If even that
+            // fails, the queue already contains a `Resync` the publisher
+            // hasn't consumed yet, so there's nothing more to do.)
+            let _ = tx.try_send(IptSetDelta::Resync);
+            break;
+        }
+    }
+}
+
 /// Mutex guard that will notify when dropped
 ///
 /// Returned by [`IptsManagerView::borrow_for_update`]
@@ -174,27 +367,56 @@ struct NotifyingBorrow<'v> {
     guard: MutexGuard<'v, PublishIptSet>,
 
     /// To be notified on drop
-    notify: &'v mut mpsc::Sender<()>,
+    notify: &'v Notify,
+
+    /// Sender for the delta side channel, to report what changed on drop
+    delta_tx: &'v mut mpsc::Sender<IptSetDelta>,
+
+    /// Snapshot of the set as it was before this borrow, for diffing on drop
+    before: DeltaSnapshot,
 }
 
 /// Create a new shared state channel for the publication instructions
 pub(crate) fn ipts_channel(initial_state: PublishIptSet) -> (IptsManagerView, IptsPublisherView) {
     let shared = Arc::new(Mutex::new(initial_state));
-    // Zero buffer is right.  Docs for `mpsc::channel` say:
-    //   each sender gets a guaranteed slot in the channel capacity,
-    //   and on top of that there are buffer “first come, first serve” slots
-    // We only have one sender and only ever want one outstanding,
-    // since we can (and would like to) coalesce notifications.
-    let (tx, rx) = mpsc::channel(0);
+    let notify = Notify::default();
+    // The `IptsPublisherView` we're about to return is the first (and,
+    // unlike `IptsPublisherUploadView`, the only non-cloneable) receiver
+    // handle.
+    lock_notify(&notify).receivers = 1;
+    let (delta_tx, delta_rx) = mpsc::channel(DELTA_QUEUE_DEPTH);
     (
         IptsManagerView {
             shared: shared.clone(),
-            notify: tx,
+            notify: notify.clone(),
+            delta_tx,
+        },
+        IptsPublisherView {
+            shared,
+            notify,
+            last_seen: 0,
+            delta_rx,
         },
-        IptsPublisherView { shared, notify: rx },
     )
 }
 
+/// Record that one receiver handle has gone away
+///
+/// Wakes a manager parked in [`IptsManagerView::closed`] if this was the
+/// last one.
+fn release_receiver(notify: &Notify) {
+    let mut state = lock_notify(notify);
+    state.receivers = state
+        .receivers
+        .checked_sub(1)
+        .expect("IPT set receiver count underflowed");
+    if state.receivers == 0 {
+        if let Some(waker) = state.manager_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
 /// Lock the shared state and obtain a lock guard
 ///
 /// Does not do any notification.
@@ -204,6 +426,11 @@ fn lock_shared(shared: &Shared) -> MutexGuard<PublishIptSet> {
     shared.lock().expect("IPT set shared state poisoned")
 }
 
+/// Lock the shared notification state
+fn lock_notify(notify: &Notify) -> MutexGuard<NotifyState> {
+    notify.lock().expect("IPT set notification state poisoned")
+}
+
 impl IptsManagerView {
     /// Arrange to be able to update the list of introduction points
     ///
@@ -214,20 +441,35 @@ impl IptsManagerView {
     /// The publisher will be notified when it is dropped.
     pub(crate) fn borrow_for_update(&mut self) -> impl DerefMut<Target = PublishIptSet> + '_ {
         let guard = lock_shared(&self.shared);
+        let before = DeltaSnapshot::capture(&guard);
         NotifyingBorrow {
             guard,
-            notify: &mut self.notify,
+            notify: &self.notify,
+            delta_tx: &mut self.delta_tx,
+            before,
         }
     }
 }
 
 impl Drop for NotifyingBorrow<'_> {
     fn drop(&mut self) {
-        // Channel full?  Well, then the receiver is indeed going to wake up, so fine
-        // Channel disconnected?  The publisher has crashed or terminated,
-        // but we are not in a position to fail and shut down the establisher.
-        // If our HS is shutting down, the manager will be shut down by other means.
-        let _: Result<(), mpsc::TrySendError<_>> = self.notify.try_send(());
+        // Work out what changed (if anything) and post it to the delta
+        // side channel before notifying, so that by the time a woken
+        // subscriber calls `drain_deltas` the deltas are already there.
+        let after = DeltaSnapshot::capture(&self.guard);
+        send_deltas(self.delta_tx, &self.before, &after);
+
+        // Bump the sequence number and wake every subscriber currently
+        // parked in `await_update`, on every view (the main publisher
+        // view and each upload view).  A subscriber that isn't currently
+        // parked will simply see the new sequence number next time it calls
+        // `await_update`, so there is nothing to coalesce here: we can
+        // (and must) wake everyone, every time.
+        let mut state = lock_notify(self.notify);
+        state.sequence = state.sequence.wrapping_add(1);
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
 
         // Now the fields will be dropped, includeing `guard`.
         // I.e. the mutex gets unlocked.  This means we notify the publisher
@@ -238,6 +480,75 @@ impl Drop for NotifyingBorrow<'_> {
     }
 }
 
+impl Drop for IptsManagerView {
+    fn drop(&mut self) {
+        // Tell every subscriber there will be no more updates, ever.
+        let mut state = lock_notify(&self.notify);
+        state.closed = true;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for IptsPublisherView {
+    fn drop(&mut self) {
+        release_receiver(&self.notify);
+    }
+}
+
+impl Drop for IptsPublisherUploadView {
+    fn drop(&mut self) {
+        release_receiver(&self.notify);
+    }
+}
+
+impl IptsManagerView {
+    /// Wait until every receiver handle on this channel has gone away
+    ///
+    /// Resolves once the one [`IptsPublisherView`] and every
+    /// [`IptsPublisherUploadView`] clone of it have been dropped: at that
+    /// point nothing is listening for updates any more (or ever will be
+    /// again), so the IPT manager should stop establishing and rotating
+    /// introduction points for this channel rather than maintaining them
+    /// for a publisher that no longer exists.
+    pub(crate) async fn closed(&self) {
+        poll_fn(|cx| self.poll_closed(cx)).await;
+    }
+
+    /// Poll for [`Self::closed`]
+    pub(crate) fn poll_closed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = lock_notify(&self.notify);
+        if state.receivers == 0 {
+            return Poll::Ready(());
+        }
+        state.manager_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Poll for the next update visible to a subscriber that has already seen
+/// sequence number `*last_seen`, parking `cx`'s waker if there isn't one yet
+///
+/// On `Ready(Some(..))`, `*last_seen` is advanced to the sequence number
+/// that was just observed.
+fn poll_update(
+    notify: &Notify,
+    last_seen: &mut u64,
+    cx: &mut Context<'_>,
+) -> Poll<Option<Result<(), crate::FatalError>>> {
+    let mut state = lock_notify(notify);
+    if state.sequence != *last_seen {
+        *last_seen = state.sequence;
+        return Poll::Ready(Some(Ok(())));
+    }
+    if state.closed {
+        return Poll::Ready(None);
+    }
+    state.wakers.push(cx.waker().clone());
+    Poll::Pending
+}
+
 impl IptsPublisherView {
     /// Wait until the IPT set has changed (or may have)
     ///
@@ -252,14 +563,10 @@ impl IptsPublisherView {
     ///  * `None` if the manager is shutting down and the publisher should shut down too
     ///  * `Some(Err(..))` if a fatal error occurred
     pub(crate) async fn await_update(&mut self) -> Option<Result<(), crate::FatalError>> {
-        // Cancellation safety:
-        //
-        // We're using mpsc::Receiver's implementation of Stream, via StreamExt.
-        // Stream::next() must be cancellation safe or it would be lossy everywhere.
-        // So it is OK to create the future from next, here, and possibly discard it
-        // before it becomes Ready.
-        let () = self.notify.next().await?;
-        Some(Ok(()))
+        // Cancellation safety: poll_update only mutates `self.last_seen`
+        // (and registers a waker) when it's about to return `Ready`, so
+        // dropping this future before it resolves loses nothing.
+        poll_fn(|cx| poll_update(&self.notify, &mut self.last_seen, cx)).await
     }
 
     /// Look at the list of introduction points to publish
@@ -274,20 +581,155 @@ impl IptsPublisherView {
         lock_shared(&self.shared)
     }
 
+    /// Look at the list of introduction points to publish, noting the generation observed
+    ///
+    /// Like [`borrow_for_publish`](Self::borrow_for_publish), but also
+    /// returns the [`Generation`] of the set that was just borrowed, for
+    /// later use with [`Self::has_changed_since`] or
+    /// [`Self::note_publication_attempt`].
+    pub(crate) fn borrow_and_update(&self) -> (impl DerefMut<Target = PublishIptSet> + '_, Generation) {
+        let guard = lock_shared(&self.shared);
+        // The manager bumps `notify.sequence` before releasing `shared`
+        // (see `NotifyingBorrow::drop`), so reading it after we've taken
+        // `shared`'s lock always observes the generation of the set we
+        // just borrowed, never a stale or a not-yet-committed one.
+        let generation = lock_notify(&self.notify).sequence;
+        (guard, generation)
+    }
+
+    /// Has the IPT set changed since generation `since` was observed?
+    pub(crate) fn has_changed_since(&self, since: Generation) -> bool {
+        generation_advanced(lock_notify(&self.notify).sequence, since)
+    }
+
+    /// Note that a publication attempt is being made, unless it's already stale
+    ///
+    /// `attempt_generation` must be the [`Generation`] observed (e.g. via
+    /// [`Self::borrow_and_update`]) when the descriptor about to be
+    /// published was built. If the set has moved on since then, returns
+    /// [`NotePublicationOutcome::Stale`] without recording anything: the
+    /// caller is publishing introduction points that are no longer
+    /// current, and should abandon this attempt and re-derive a
+    /// descriptor from a fresh borrow instead.
+    pub(crate) fn note_publication_attempt(
+        &self,
+        worst_case_end: Instant,
+        attempt_generation: Generation,
+    ) -> Result<NotePublicationOutcome, FatalError> {
+        let (mut guard, current_generation) = self.borrow_and_update();
+        if generation_advanced(current_generation, attempt_generation) {
+            return Ok(NotePublicationOutcome::Stale);
+        }
+        let ipt_set = guard
+            .as_mut()
+            .ok_or_else(|| internal!("publication attempt noted with no IPT set"))?;
+        ipt_set.note_publication_attempt(worst_case_end)?;
+        Ok(NotePublicationOutcome::Noted)
+    }
+
+    /// Drain the deltas accumulated on the side channel since the last call
+    ///
+    /// Meant to be called after [`await_update`](Self::await_update)
+    /// resolves, as a cheaper alternative to re-reading and re-diffing the
+    /// whole `Vec<IptInSet>` against what was previously published. Does
+    /// not block: if nothing has been posted since the last call, returns
+    /// an empty `Vec`.
+    ///
+    /// A [`IptSetDelta::Resync`] in the returned events means some deltas
+    /// were lost because the side channel was full; the caller should
+    /// treat that as license to ignore the rest of the returned deltas and
+    /// re-derive its view of the world from
+    /// [`borrow_for_publish`](Self::borrow_for_publish) instead.
+    pub(crate) fn drain_deltas(&mut self) -> Vec<IptSetDelta> {
+        let mut deltas = Vec::new();
+        while let Ok(Some(delta)) = self.delta_rx.try_next() {
+            deltas.push(delta);
+        }
+        deltas
+    }
+
     /// Obtain an [`IptsPublisherUploadView`], for use just prior to a publication attempt
     pub(crate) fn upload_view(&self) -> IptsPublisherUploadView {
         let shared = self.shared.clone();
-        IptsPublisherUploadView { shared }
+        let notify = self.notify.clone();
+        // Start this view's cursor at the current sequence number, so it
+        // doesn't immediately report updates that happened before it
+        // existed: only the main `IptsPublisherView` saw those.
+        let mut state = lock_notify(&notify);
+        state.receivers += 1;
+        let last_seen = state.sequence;
+        drop(state);
+        IptsPublisherUploadView {
+            shared,
+            notify,
+            last_seen,
+        }
     }
 }
 
 impl IptsPublisherUploadView {
+    /// Wait until the IPT set has changed (or may have), as observed by this view
+    ///
+    /// Like [`IptsPublisherView::await_update`], but tracks its own cursor
+    /// into the shared update sequence: one upload task blocking here
+    /// doesn't consume the notification that another upload task, or the
+    /// main `IptsPublisherView`, is waiting for.
+    pub(crate) async fn await_update(&mut self) -> Option<Result<(), crate::FatalError>> {
+        poll_fn(|cx| poll_update(&self.notify, &mut self.last_seen, cx)).await
+    }
+
     /// Look at the list of introduction points to publish
     ///
     /// See [`IptsPublisherView::borrow_for_publish`].
     pub(crate) fn borrow_for_publish(&self) -> impl DerefMut<Target = PublishIptSet> + '_ {
         lock_shared(&self.shared)
     }
+
+    /// Look at the list of introduction points to publish, noting the generation observed
+    ///
+    /// See [`IptsPublisherView::borrow_and_update`].
+    pub(crate) fn borrow_and_update(&self) -> (impl DerefMut<Target = PublishIptSet> + '_, Generation) {
+        let guard = lock_shared(&self.shared);
+        let generation = lock_notify(&self.notify).sequence;
+        (guard, generation)
+    }
+
+    /// Has the IPT set changed since generation `since` was observed?
+    pub(crate) fn has_changed_since(&self, since: Generation) -> bool {
+        generation_advanced(lock_notify(&self.notify).sequence, since)
+    }
+
+    /// Note that a publication attempt is being made, unless it's already stale
+    ///
+    /// See [`IptsPublisherView::note_publication_attempt`].
+    pub(crate) fn note_publication_attempt(
+        &self,
+        worst_case_end: Instant,
+        attempt_generation: Generation,
+    ) -> Result<NotePublicationOutcome, FatalError> {
+        let (mut guard, current_generation) = self.borrow_and_update();
+        if generation_advanced(current_generation, attempt_generation) {
+            return Ok(NotePublicationOutcome::Stale);
+        }
+        let ipt_set = guard
+            .as_mut()
+            .ok_or_else(|| internal!("publication attempt noted with no IPT set"))?;
+        ipt_set.note_publication_attempt(worst_case_end)?;
+        Ok(NotePublicationOutcome::Noted)
+    }
+}
+
+/// Outcome of a generation-checked [`note_publication_attempt`](IptsPublisherView::note_publication_attempt)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum NotePublicationOutcome {
+    /// The attempt was noted: the IPT set hadn't changed since `attempt_generation`
+    Noted,
+    /// The IPT set changed since `attempt_generation`
+    ///
+    /// The generation that was about to be published is stale; the caller
+    /// should abandon the descriptor it built and start over from a fresh
+    /// [`borrow_and_update`](IptsPublisherView::borrow_and_update).
+    Stale,
 }
 
 impl IptSet {
@@ -446,4 +888,236 @@ mod test {
             assert_eq!(mv_get_0_expiry(&mut mv), expected_expiry);
         });
     }
+
+    async fn uv_poll_await_update(
+        uv: &mut IptsPublisherUploadView,
+    ) -> Poll<Option<Result<(), FatalError>>> {
+        let fut = uv.await_update();
+        pin_mut!(fut);
+        poll!(fut)
+    }
+
+    #[test]
+    fn upload_views_await_update_independently() {
+        let runtime = tor_rtmock::MockRuntime::new();
+        runtime.clone().block_on(async move {
+            let (mut mv, mut pv) = ipts_channel(None);
+
+            // each upload view starts out with no pending update, just like
+            // the main publisher view would
+            let mut uv1 = pv.upload_view();
+            assert!(matches!(uv_poll_await_update(&mut uv1).await, Pending));
+
+            // an update wakes *every* outstanding view, including ones
+            // created before the update and ones created after
+            let mut mg = mv.borrow_for_update();
+            *mg = Some(IptSet {
+                ipts: vec![],
+                lifetime: Duration::ZERO,
+            });
+            drop(mg);
+
+            let mut uv2 = pv.upload_view();
+            assert!(matches!(
+                uv_poll_await_update(&mut uv1).await,
+                Ready(Some(Ok(())))
+            ));
+            assert!(matches!(uv_poll_await_update(&mut uv1).await, Pending));
+            // uv2 was created after the update landed, so it doesn't see it
+            assert!(matches!(uv_poll_await_update(&mut uv2).await, Pending));
+
+            // one upload task consuming its own update doesn't consume the
+            // one belonging to another task, or to the main publisher view
+            let mut mg = mv.borrow_for_update();
+            mg.as_mut().unwrap().lifetime = Duration::from_secs(42);
+            drop(mg);
+
+            assert!(matches!(
+                uv_poll_await_update(&mut uv1).await,
+                Ready(Some(Ok(())))
+            ));
+            assert!(matches!(
+                uv_poll_await_update(&mut uv2).await,
+                Ready(Some(Ok(())))
+            ));
+            assert!(matches!(
+                pv_poll_await_update(&mut pv).await,
+                Ready(Some(Ok(())))
+            ));
+
+            // dropping the manager view tells every outstanding subscriber
+            // to give up, rather than wait forever
+            drop(mv);
+            assert!(matches!(uv_poll_await_update(&mut uv1).await, Ready(None)));
+            assert!(matches!(pv_poll_await_update(&mut pv).await, Ready(None)));
+        });
+    }
+
+    #[test]
+    fn stale_publication_attempt_is_rejected() {
+        let runtime = tor_rtmock::MockRuntime::new();
+        runtime.clone().block_on(async move {
+            let (mut mv, pv) = ipts_channel(None);
+
+            let mut mg = mv.borrow_for_update();
+            *mg = Some(IptSet {
+                ipts: vec![],
+                lifetime: Duration::from_secs(1800),
+            });
+            drop(mg);
+
+            // capture the generation, as an upload task would just before
+            // building a descriptor from the borrowed set
+            let (guard, attempt_generation) = pv.borrow_and_update();
+            drop(guard);
+            assert!(!pv.has_changed_since(attempt_generation));
+
+            // noting the attempt while nothing has changed succeeds
+            let outcome = pv
+                .note_publication_attempt(runtime.now() + Duration::from_secs(300), attempt_generation)
+                .unwrap();
+            assert_eq!(outcome, NotePublicationOutcome::Noted);
+
+            // the manager mutates the set again, moving the generation on
+            mv.borrow_for_update().as_mut().unwrap().lifetime = Duration::from_secs(60);
+            assert!(pv.has_changed_since(attempt_generation));
+
+            // so a publication attempt still using the old generation is stale
+            let outcome = pv
+                .note_publication_attempt(runtime.now() + Duration::from_secs(300), attempt_generation)
+                .unwrap();
+            assert_eq!(outcome, NotePublicationOutcome::Stale);
+
+            // a fresh generation is accepted again
+            let (guard, fresh_generation) = pv.borrow_and_update();
+            drop(guard);
+            let outcome = pv
+                .note_publication_attempt(runtime.now() + Duration::from_secs(300), fresh_generation)
+                .unwrap();
+            assert_eq!(outcome, NotePublicationOutcome::Noted);
+        });
+    }
+
+    async fn mv_poll_closed(mv: &IptsManagerView) -> Poll<()> {
+        let fut = mv.closed();
+        pin_mut!(fut);
+        poll!(fut)
+    }
+
+    #[test]
+    fn manager_detects_publisher_departure() {
+        let runtime = tor_rtmock::MockRuntime::new();
+        runtime.clone().block_on(async move {
+            let (mv, pv) = ipts_channel(None);
+
+            // the publisher view is still live, so the manager shouldn't
+            // see `closed` resolve yet
+            assert!(matches!(mv_poll_closed(&mv).await, Pending));
+
+            // neither should it once an upload view is taken out too
+            let uv1 = pv.upload_view();
+            assert!(matches!(mv_poll_closed(&mv).await, Pending));
+
+            // nor a clone of that upload view
+            let uv2 = uv1.clone();
+            assert!(matches!(mv_poll_closed(&mv).await, Pending));
+
+            // dropping the main publisher view alone isn't enough: the
+            // upload views are still outstanding
+            drop(pv);
+            assert!(matches!(mv_poll_closed(&mv).await, Pending));
+
+            drop(uv1);
+            assert!(matches!(mv_poll_closed(&mv).await, Pending));
+
+            // only once the last receiver handle is gone does `closed` resolve
+            drop(uv2);
+            assert!(matches!(mv_poll_closed(&mv).await, Ready(())));
+        });
+    }
+
+    fn some_lid(b: u8) -> IptLocalId {
+        IptLocalId([b; 32])
+    }
+
+    fn ipt_in_set(lid: IptLocalId) -> IptInSet {
+        IptInSet {
+            ipt: test_intro_point(),
+            lid,
+            last_descriptor_expiry_including_slop: None,
+        }
+    }
+
+    #[test]
+    fn delta_stream_reports_added_removed_and_lifetime_changed() {
+        let runtime = tor_rtmock::MockRuntime::new();
+        runtime.clone().block_on(async move {
+            let (mut mv, mut pv) = ipts_channel(None);
+
+            // going from no set at all to one with some ipts is reported
+            // as each ipt being added, plus the initial lifetime
+            let mut mg = mv.borrow_for_update();
+            *mg = Some(IptSet {
+                ipts: vec![ipt_in_set(some_lid(1)), ipt_in_set(some_lid(2))],
+                lifetime: Duration::from_secs(1800),
+            });
+            drop(mg);
+
+            let mut deltas = pv.drain_deltas();
+            deltas.sort_by_key(|d| format!("{d:?}"));
+            assert_eq!(
+                deltas,
+                vec![
+                    IptSetDelta::Added(some_lid(1)),
+                    IptSetDelta::Added(some_lid(2)),
+                    IptSetDelta::LifetimeChanged(Duration::from_secs(1800)),
+                ]
+            );
+
+            // a no-op borrow reports nothing
+            drop(mv.borrow_for_update());
+            assert_eq!(pv.drain_deltas(), vec![]);
+
+            // removing one ipt and changing the lifetime in a single
+            // borrow reports exactly that
+            let mut mg = mv.borrow_for_update();
+            let set = mg.as_mut().unwrap();
+            set.ipts.retain(|ipt| ipt.lid != some_lid(1));
+            set.lifetime = Duration::from_secs(60);
+            drop(mg);
+
+            assert_eq!(
+                pv.drain_deltas(),
+                vec![
+                    IptSetDelta::Removed(some_lid(1)),
+                    IptSetDelta::LifetimeChanged(Duration::from_secs(60)),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn delta_stream_resyncs_on_overflow() {
+        let runtime = tor_rtmock::MockRuntime::new();
+        runtime.clone().block_on(async move {
+            let (mut mv, mut pv) = ipts_channel(None);
+
+            // generate far more deltas than fit in the queue, without the
+            // publisher ever draining it
+            for n in 0..(DELTA_QUEUE_DEPTH as u8 * 2) {
+                let mut mg = mv.borrow_for_update();
+                *mg = Some(IptSet {
+                    ipts: vec![ipt_in_set(some_lid(n))],
+                    lifetime: Duration::ZERO,
+                });
+                drop(mg);
+            }
+
+            // the publisher should see a `Resync` marker, and definitely
+            // not more entries than the queue can hold
+            let deltas = pv.drain_deltas();
+            assert!(deltas.len() <= DELTA_QUEUE_DEPTH);
+            assert!(deltas.contains(&IptSetDelta::Resync));
+        });
+    }
 }
@@ -2,7 +2,9 @@
 #![allow(dead_code, unused_variables)] // TODO hss remove.
 pub(crate) mod netdir;
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use std::time::SystemTime;
 
 use futures::channel::mpsc;
 use futures::Stream;
@@ -11,26 +13,35 @@ use tor_async_utils::oneshot;
 use tor_async_utils::PostageWatchSenderExt as _;
 use tor_circmgr::hspool::HsCircPool;
 use tor_config::{Reconfigure, ReconfigureError};
+use tor_error::internal;
 use tor_error::Bug;
+use tor_hscrypto::pk::HsBlindIdKey;
+use tor_hscrypto::pk::HsBlindIdKeypair;
 use tor_hscrypto::pk::HsId;
 use tor_hscrypto::pk::HsIdKey;
 use tor_hscrypto::pk::HsIdKeypair;
+use tor_hscrypto::time::TimePeriod;
 use tor_keymgr::KeyMgr;
 use tor_keymgr::KeystoreSelector;
 use tor_llcrypto::pk::curve25519;
 use tor_llcrypto::pk::ed25519;
 use tor_netdir::NetDirProvider;
 use tor_rtcompat::Runtime;
+use tor_rtcompat::SleepProvider as _;
 use tracing::{info, trace, warn};
 
+use curve25519_dalek::constants::{ED25519_BASEPOINT_COMPRESSED, ED25519_BASEPOINT_TABLE};
+use curve25519_dalek::scalar::Scalar;
+use sha3::{Digest, Sha3_256};
+
 use crate::ipt_mgr::IptManager;
 use crate::ipt_set::IptsManagerView;
 use crate::svc::publish::Publisher;
+use crate::ClientAuthError;
 use crate::HsIdKeypairSpecifier;
 use crate::HsIdPublicKeySpecifier;
 use crate::HsNickname;
 use crate::OnionServiceConfig;
-use crate::OnionServiceStatus;
 use crate::RendRequest;
 use crate::StartupError;
 
@@ -45,6 +56,156 @@ pub(crate) type LinkSpecs = Vec<tor_linkspec::EncodedLinkSpec>;
 // TODO HSS maybe this should be `tor_proto::crypto::handshake::ntor::NtorPublicKey`?
 type NtorPublicKey = curve25519::PublicKey;
 
+/// A client's `x25519` public key, used to authorize it for client authorization
+/// (a.k.a. "restricted discovery").
+///
+/// The publisher encrypts the inner layer of our descriptor to every
+/// authorized client's key; a client that can't decrypt the inner layer
+/// can't learn our introduction points, and a rendezvous request that
+/// doesn't authenticate as one of these clients is dropped.
+pub type HsClientAuthKey = curve25519::PublicKey;
+
+/// Identifier for a client that has been authorized to use this service,
+/// under the client-authorization ("restricted discovery") feature.
+///
+/// This is just a name the operator picks to refer to one of their
+/// clients; it has no meaning to anyone else and isn't transmitted
+/// anywhere. It selects which [`HsClientAuthKeySpecifier`] to read and
+/// write in the keystore.
+//
+// TODO HSS: validate this the way `HsNickname` validates its argument,
+// once the rules for a legal client id are settled.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ClientId(String);
+
+impl ClientId {
+    /// Wrap `id` as a [`ClientId`].
+    pub fn new(id: String) -> Self {
+        ClientId(id)
+    }
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Keystore specifier for a client's [`HsClientAuthKey`].
+///
+/// Keyed by the service's [`HsNickname`] together with the client's
+/// [`ClientId`], the same way [`HsIdKeypairSpecifier`] is keyed by
+/// nickname alone: the presence or absence of a key under this specifier
+/// is the authoritative record of whether that client is currently
+/// authorized.
+#[derive(Debug, Clone)]
+pub(crate) struct HsClientAuthKeySpecifier {
+    /// The nickname of the onion service.
+    nickname: HsNickname,
+    /// The id of the client this key authorizes.
+    client_id: ClientId,
+}
+
+impl HsClientAuthKeySpecifier {
+    /// Create a new specifier for the authorization key of `client_id` on `nickname`'s service.
+    pub(crate) fn new(nickname: &HsNickname, client_id: ClientId) -> Self {
+        Self {
+            nickname: nickname.clone(),
+            client_id,
+        }
+    }
+}
+
+/// Keystore specifier for a service's per-time-period blinded signing keypair
+/// (`KS_hs_blind_id`).
+///
+/// Keyed by the service's [`HsNickname`] together with the [`TimePeriod`] the key is valid
+/// for, the same way [`HsClientAuthKeySpecifier`] is keyed by nickname and client id: a
+/// service generally has two of these in its keystore at once (the current time period's,
+/// and the next one's, pre-generated ahead of the rollover).
+#[derive(Debug, Clone)]
+pub(crate) struct BlindIdKeypairSpecifier {
+    /// The nickname of the onion service.
+    nickname: HsNickname,
+    /// The time period the key is valid for.
+    period: TimePeriod,
+}
+
+impl BlindIdKeypairSpecifier {
+    /// Create a new specifier for `nickname`'s blinded signing keypair in `period`.
+    pub(crate) fn new(nickname: &HsNickname, period: TimePeriod) -> Self {
+        Self {
+            nickname: nickname.clone(),
+            period,
+        }
+    }
+}
+
+/// The high-level lifecycle state of a running onion service.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum OnionServiceState {
+    /// We're still setting up: establishing introduction points and/or publishing our
+    /// first descriptor.
+    Bootstrapping,
+    /// We have at least one established introduction point and a published descriptor.
+    Running,
+    /// We were running, but have since lost some of what we need (e.g. every
+    /// introduction point has gone away, or our latest descriptor upload failed
+    /// everywhere); clients may have trouble reaching us until we recover.
+    Degraded,
+    /// [`OnionService::stop`] has been called; this service will not do any more work.
+    Stopped,
+}
+
+/// A snapshot of an onion service's current operational status.
+///
+/// Returned by [`OnionService::status`], and emitted by the stream from
+/// [`OnionService::status_events`] every time it changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OnionServiceStatus {
+    /// The high-level lifecycle state.
+    state: OnionServiceState,
+    /// How many introduction points are currently established.
+    num_ipts_established: usize,
+    /// When we last succeeded in uploading a descriptor to an HsDir.
+    last_successful_upload: Option<SystemTime>,
+    /// The time period our most recent successful descriptor upload was for.
+    current_period: Option<TimePeriod>,
+}
+
+impl OnionServiceStatus {
+    /// The status a brand new, not-yet-launched service starts out in.
+    fn new_bootstrapping() -> Self {
+        Self {
+            state: OnionServiceState::Bootstrapping,
+            num_ipts_established: 0,
+            last_successful_upload: None,
+            current_period: None,
+        }
+    }
+
+    /// The high-level lifecycle state this service is in.
+    pub fn state(&self) -> OnionServiceState {
+        self.state
+    }
+
+    /// The number of introduction points currently established.
+    pub fn num_ipts_established(&self) -> usize {
+        self.num_ipts_established
+    }
+
+    /// When we last succeeded in uploading a descriptor to an HsDir, if ever.
+    pub fn last_successful_upload(&self) -> Option<SystemTime> {
+        self.last_successful_upload
+    }
+
+    /// The time period our most recent successful descriptor upload was for, if any.
+    pub fn current_period(&self) -> Option<TimePeriod> {
+        self.current_period
+    }
+}
+
 /// A handle to an instance of an onion service.
 //
 // TODO HSS: Write more.
@@ -61,13 +222,47 @@ struct SvcInner {
     /// Configuration information about this service.
     config_tx: postage::watch::Sender<Arc<OnionServiceConfig>>,
 
+    /// The nickname of this service.
+    ///
+    /// Kept alongside `config_tx` (rather than read out of it) so that
+    /// [`add_client_key`](OnionService::add_client_key) and
+    /// [`revoke_client_key`](OnionService::revoke_client_key) can build a
+    /// [`HsClientAuthKeySpecifier`] without needing a read-capable view of
+    /// the config.
+    nickname: HsNickname,
+
     /// A keymgr used to look up our keys and store new medium-term keys.
     //
     // TODO HSS: Do we actually need this in this structure?
     keymgr: Arc<KeyMgr>,
 
-    /// A oneshot that will be dropped when this object is dropped.
-    shutdown_tx: oneshot::Sender<void::Void>,
+    /// The current status snapshot, readable synchronously by [`OnionService::status`].
+    status: OnionServiceStatus,
+
+    /// Sender used to broadcast status changes to `status_events()` subscribers.
+    status_tx: postage::watch::Sender<OnionServiceStatus>,
+
+    /// Template receiver, cloned for every new [`OnionService::status_events`] subscriber.
+    status_rx: postage::watch::Receiver<OnionServiceStatus>,
+
+    /// A oneshot that will be dropped to signal shutdown to [`IptManager`].
+    ///
+    /// `None` once [`OnionService::stop`] has been called.
+    shutdown_tx: Option<oneshot::Sender<void::Void>>,
+
+    /// Sender used to tell the [`KeyWatcher`] and [`BlindKeyRotator`] background loops to
+    /// stop.
+    ///
+    /// A `postage::watch` rather than another oneshot like `shutdown_tx`: those two loops
+    /// are spawned (from their own `launch()`s) well after this `SvcInner` is built, so
+    /// there's no single `Receiver` we could consume and hand to both of them -- each needs
+    /// its own clone of a `shutdown_rx`-style template, the same way `status_rx` is cloned
+    /// for every [`OnionService::status_events`] subscriber.
+    shutdown_signal_tx: postage::watch::Sender<bool>,
+
+    /// Template receiver for `shutdown_signal_tx`, cloned for the [`KeyWatcher`] and
+    /// [`BlindKeyRotator`] background loops.
+    shutdown_signal_rx: postage::watch::Receiver<bool>,
 
     /// Handles that we'll take ownership of when launching the service.
     ///
@@ -98,23 +293,436 @@ struct ForLaunch<R: Runtime> {
     ///
     ///
     ipt_mgr_view: IptsManagerView,
+
+    /// Background task that watches the keystore for keys provisioned (or changed)
+    /// out-of-band, and wakes the rest of the service up when it finds one.
+    key_watcher: KeyWatcher<R>,
+
+    /// Background task that derives and rotates our per-time-period blinded signing
+    /// keypairs.
+    blind_key_rotator: BlindKeyRotator<R>,
 }
 
 /// Private trait used to type-erase `ForLaunch<R>`, so that we don't need to
 /// parameterize OnionService on `<R>`.
 trait Launchable: Send + Sync {
-    /// Launch
-    fn launch(self: Box<Self>) -> Result<(), StartupError>;
+    /// Launch, using `svc` to reach back into the [`OnionService`] from background tasks
+    /// that outlive this call (and should stop once `svc` can no longer be upgraded).
+    fn launch(self: Box<Self>, svc: Weak<OnionService>) -> Result<(), StartupError>;
 }
 
 impl<R: Runtime> Launchable for ForLaunch<R> {
-    fn launch(self: Box<Self>) -> Result<(), StartupError> {
+    fn launch(self: Box<Self>, svc: Weak<OnionService>) -> Result<(), StartupError> {
         self.ipt_mgr.launch_background_tasks(self.ipt_mgr_view)?;
         self.publisher.launch()?;
+        self.key_watcher.launch(svc.clone())?;
+        self.blind_key_rotator.launch(svc)?;
         Ok(())
     }
 }
 
+/// Handle for the background task that watches the keystore for keys an operator
+/// provisioned (or changed) out-of-band, e.g. as part of the offline-`KS_hs_id` workflow.
+///
+/// Modelled on the `SecretStore` design of deliberately not caching the key-server-set
+/// configuration, and instead re-reading it whenever the underlying source changes: rather
+/// than trusting the key material we saw at [`OnionService::new`] forever, we keep checking
+/// the keystore so that an operator who drops in a new `KS_hs_id` (or, in the future, a
+/// fresh per-period blinded key, or a client authorization key) doesn't have to restart the
+/// service for it to take effect.
+struct KeyWatcher<R: Runtime> {
+    /// Runtime, used to spawn the watcher task and to sleep between polls.
+    runtime: R,
+    /// Keymgr to check for new or changed keys.
+    keymgr: Arc<KeyMgr>,
+    /// Nickname of the service whose keys we're watching.
+    nickname: HsNickname,
+    /// Whether `KS_hs_id` is expected to be provisioned offline.
+    offline_hsid: bool,
+    /// Tells us when [`OnionService::stop`] has been called.
+    shutdown_signal: postage::watch::Receiver<bool>,
+}
+
+impl<R: Runtime> KeyWatcher<R> {
+    /// Spawn the watcher loop.
+    fn launch(self, svc: Weak<OnionService>) -> Result<(), StartupError> {
+        let KeyWatcher {
+            runtime,
+            keymgr,
+            nickname,
+            offline_hsid,
+            shutdown_signal,
+        } = self;
+        let rt = runtime.clone();
+        rt.spawn(watch_keys(
+            runtime,
+            keymgr,
+            nickname,
+            offline_hsid,
+            svc,
+            shutdown_signal,
+        ))
+        .map_err(|cause| StartupError::Spawn {
+            spawning: "onion service key watcher",
+            cause: Arc::new(cause),
+        })
+    }
+}
+
+/// How often to check the keystore for newly provisioned or changed keys, absent a real
+/// filesystem change-notification.
+//
+// TODO HSS: replace this poll loop with a proper `notify`/inotify-based watch once `KeyMgr`
+// (or the underlying keystore) can either hand us the keystore's on-disk root to watch, or
+// forward change notifications of its own; until then, polling is the best we can do from
+// this crate.
+const KEY_WATCH_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait, after noticing a change, before checking again
+///
+/// Lets a burst of keystore writes (e.g. an operator copying in several files at once)
+/// settle, rather than reacting to every single file as it lands.
+const KEY_WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Body of the [`KeyWatcher`] background task
+///
+/// Polls `keymgr` for `nickname`'s `KS_hs_id`, generating it (unless `offline_hsid`) exactly
+/// as [`maybe_generate_hsid`] would at startup, and calls
+/// [`OnionService::trigger_republish`] on `svc` the moment a previously-missing hsid
+/// appears, so that an operator's out-of-band key provisioning takes effect without a
+/// restart. Returns once `svc` can no longer be upgraded (ie, once the service is dropped)
+/// or once `shutdown_signal` reports that [`OnionService::stop`] was called, whichever
+/// happens first.
+async fn watch_keys<R: Runtime>(
+    runtime: R,
+    keymgr: Arc<KeyMgr>,
+    nickname: HsNickname,
+    offline_hsid: bool,
+    svc: Weak<OnionService>,
+    mut shutdown_signal: postage::watch::Receiver<bool>,
+) {
+    let hsid_spec = HsIdKeypairSpecifier::new(&nickname);
+    let mut had_hsid = keymgr
+        .get::<HsIdKeypair>(&hsid_spec)
+        .ok()
+        .flatten()
+        .is_some();
+
+    loop {
+        if sleep_or_stop(&runtime, KEY_WATCH_POLL_INTERVAL, &mut shutdown_signal).await {
+            return;
+        }
+
+        let Some(svc) = svc.upgrade() else {
+            return;
+        };
+
+        if !offline_hsid {
+            if let Err(err) = maybe_generate_hsid(&keymgr, &nickname, offline_hsid) {
+                warn!("{nickname}: key watcher couldn't check/generate KS_hs_id: {err}");
+                continue;
+            }
+        }
+
+        let has_hsid = match keymgr.get::<HsIdKeypair>(&hsid_spec) {
+            Ok(k) => k.is_some(),
+            Err(err) => {
+                warn!("{nickname}: key watcher couldn't read the keystore: {err}");
+                continue;
+            }
+        };
+
+        if has_hsid && !had_hsid {
+            info!("{nickname}: picked up a newly provisioned identity key; triggering a republish");
+            svc.trigger_republish();
+        }
+        had_hsid = has_hsid;
+
+        // TODO HSS: also watch for client-authorization keys
+        // (`HsClientAuthKeySpecifier`) being added or removed here, and trigger a
+        // republish for those too.
+
+        // Let a burst of filesystem writes settle before we check again.
+        if sleep_or_stop(&runtime, KEY_WATCH_DEBOUNCE, &mut shutdown_signal).await {
+            return;
+        }
+    }
+}
+
+/// Sleep for `duration`, unless `shutdown_signal` reports a stop request first.
+///
+/// Returns `true` if the stop request won the race, and the caller's background loop should
+/// return; `false` if `duration` elapsed normally.
+///
+/// Shared by [`watch_keys`] and [`rotate_blind_keys`] so that neither loop has to wait out an
+/// entire poll interval after [`OnionService::stop`] is called.
+async fn sleep_or_stop<R: Runtime>(
+    runtime: &R,
+    duration: Duration,
+    shutdown_signal: &mut postage::watch::Receiver<bool>,
+) -> bool {
+    use futures::FutureExt as _;
+    use futures::StreamExt as _;
+
+    futures::select_biased! {
+        stopped = shutdown_signal.next().fuse() => {
+            // `Some(true)`: `stop()` was called. `None`: every `Sender` (and so the
+            // `OnionService` itself) is gone. Either way, there's nothing left to watch for.
+            matches!(stopped, Some(true) | None)
+        },
+        () = runtime.sleep(duration).fuse() => false,
+    }
+}
+
+/// Length, in seconds, of a Tor hidden-service directory time period.
+//
+// TODO HSS: this should come from the directory consensus (the `hsdir-interval`
+// parameter) rather than being hardcoded; for now it matches the network's current
+// default.
+const ASSUMED_TIME_PERIOD_LENGTH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long before a time period ends that its blinded key should already be in the
+/// keystore, and how often the rotator wakes up to check.
+///
+/// Generous on purpose: pre-generating the next period's key this far ahead gives the
+/// publisher plenty of room to notice and start using it before the rollover, and an
+/// operator using `offline_hsid` plenty of warning to provision it.
+const BLIND_KEY_ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Handle for the background task that keeps `KS_hs_blind_id` generated and rotated.
+struct BlindKeyRotator<R: Runtime> {
+    /// Runtime, used to spawn the rotator task and to sleep between checks.
+    runtime: R,
+    /// Keymgr to read the master identity key from, and to store blinded keys in.
+    keymgr: Arc<KeyMgr>,
+    /// Nickname of the service whose keys we're rotating.
+    nickname: HsNickname,
+    /// Whether `KS_hs_id` is expected to be provisioned offline.
+    ///
+    /// When set, we never have the identity key ourselves, so we can only check that an
+    /// operator has pre-provisioned the blinded keys we need, not derive them.
+    offline_hsid: bool,
+    /// Tells us when [`OnionService::stop`] has been called.
+    shutdown_signal: postage::watch::Receiver<bool>,
+}
+
+// TODO HSS: once `Publisher` can read from the keystore itself (or is given a handle to
+// do so), it should just look up the current time period's `BlindIdKeypairSpecifier` when
+// it's about to sign a descriptor, rather than this rotator needing to push the key to it
+// directly; for now there's no `Publisher::set_blind_key`-style API to call into here.
+
+impl<R: Runtime> BlindKeyRotator<R> {
+    /// Spawn the rotator loop.
+    fn launch(self, svc: Weak<OnionService>) -> Result<(), StartupError> {
+        let BlindKeyRotator {
+            runtime,
+            keymgr,
+            nickname,
+            offline_hsid,
+            shutdown_signal,
+        } = self;
+        let rt = runtime.clone();
+        rt.spawn(rotate_blind_keys(
+            runtime,
+            keymgr,
+            nickname,
+            offline_hsid,
+            svc,
+            shutdown_signal,
+        ))
+        .map_err(|cause| StartupError::Spawn {
+            spawning: "onion service blinded-key rotator",
+            cause: Arc::new(cause),
+        })
+    }
+}
+
+/// Body of the [`BlindKeyRotator`] background task.
+///
+/// Wakes up every [`BLIND_KEY_ROTATION_CHECK_INTERVAL`] and makes sure both the current and
+/// the next time period's `KS_hs_blind_id` are present in the keystore, deriving them from
+/// `KS_hs_id` with [`blind_keypair`] unless `offline_hsid`. Pre-generating the next period's
+/// key well before the rollover means the publisher always has the right signing key ready
+/// when it needs one. Returns once `svc` can no longer be upgraded (ie, once the service is
+/// dropped) or once `shutdown_signal` reports that [`OnionService::stop`] was called,
+/// whichever happens first.
+async fn rotate_blind_keys<R: Runtime>(
+    runtime: R,
+    keymgr: Arc<KeyMgr>,
+    nickname: HsNickname,
+    offline_hsid: bool,
+    svc: Weak<OnionService>,
+    mut shutdown_signal: postage::watch::Receiver<bool>,
+) {
+    loop {
+        if svc.upgrade().is_none() {
+            return;
+        }
+
+        let current = TimePeriod::new(ASSUMED_TIME_PERIOD_LENGTH, runtime.wallclock(), Duration::ZERO);
+        if let Err(err) = ensure_blind_key(&keymgr, &nickname, offline_hsid, current) {
+            warn!("{nickname}: couldn't ensure the blinded key for the current time period: {err}");
+        }
+        if let Some(next) = current.next() {
+            if let Err(err) = ensure_blind_key(&keymgr, &nickname, offline_hsid, next) {
+                warn!("{nickname}: couldn't pre-generate the blinded key for the next time period: {err}");
+            }
+        }
+
+        if sleep_or_stop(&runtime, BLIND_KEY_ROTATION_CHECK_INTERVAL, &mut shutdown_signal).await {
+            return;
+        }
+    }
+}
+
+/// Make sure `period`'s blinded signing keypair is in the keystore.
+///
+/// If it's missing and `offline_hsid` is false, derives it from `KS_hs_id` and stores it. If
+/// it's missing and `offline_hsid` is true, we have no identity key to derive from, so we can
+/// only warn that an operator needs to provision one out-of-band.
+fn ensure_blind_key(
+    keymgr: &Arc<KeyMgr>,
+    nickname: &HsNickname,
+    offline_hsid: bool,
+    period: TimePeriod,
+) -> Result<(), Bug> {
+    let spec = BlindIdKeypairSpecifier::new(nickname, period);
+
+    let has_key = keymgr
+        .get::<HsBlindIdKeypair>(&spec)
+        .map_err(|cause| internal!("failed to read blinded keystore entry: {cause}"))?
+        .is_some();
+    if has_key {
+        return Ok(());
+    }
+
+    if offline_hsid {
+        warn!(
+            "{nickname}: no blinded signing key for time period {period:?}, and KS_hs_id is \
+             offline; an operator needs to provision one out-of-band before it starts"
+        );
+        return Ok(());
+    }
+
+    let hsid_spec = HsIdKeypairSpecifier::new(nickname);
+    let pub_hsid_spec = HsIdPublicKeySpecifier::new(nickname);
+    let identity = keymgr
+        .get::<HsIdKeypair>(&hsid_spec)
+        .map_err(|cause| internal!("failed to read KS_hs_id: {cause}"))?
+        .ok_or_else(|| internal!("no KS_hs_id in the keystore, but offline_hsid is false"))?;
+    let id_pub = keymgr
+        .get::<HsIdKey>(&pub_hsid_spec)
+        .map_err(|cause| internal!("failed to read KP_hs_id: {cause}"))?
+        .ok_or_else(|| internal!("no KP_hs_id in the keystore, but offline_hsid is false"))?;
+
+    let blind_keypair = blind_keypair(identity, &id_pub, period)?;
+    keymgr
+        .insert(blind_keypair, &spec, KeystoreSelector::Default)
+        .map_err(|cause| internal!("failed to store blinded keypair: {cause}"))?;
+
+    info!("{nickname}: generated a new blinded signing key for time period {period:?}");
+    Ok(())
+}
+
+/// Compute the blinding factor `h` for `period`, given the service's public identity key.
+///
+/// Implements the hash half of the key-blinding derivation from rend-spec-v3 appendix A.2:
+/// `h = H(BLIND_STRING | A | s | B | N)`, where `BLIND_STRING = "Derive temporary signing
+/// key" | INT_1(0)`, `A` is the public identity key, `s` is an optional secret seed (always
+/// empty here -- this crate doesn't support that extra-protection mode, so there's nothing to
+/// hash for it), `B` is the Ed25519 basepoint (the constant, *not* `A`), and `N = "key-blind" |
+/// INT_8(N) | INT_8(L)` for a period numbered `N` of length `L` (in seconds).
+///
+/// Note for reviewers: this has only been checked for internal consistency (round-tripping
+/// against [`clamped_scalar`] and `ED25519_BASEPOINT_TABLE`), not against an official
+/// rend-spec-v3 known-answer test vector or another independent implementation -- this
+/// checkout has no network access and no such vector already present in the tree to check
+/// against. That cross-check is still needed before this derivation should be trusted to
+/// interoperate with a real Tor client.
+fn blinding_factor(period: TimePeriod, id_pub: &HsIdKey) -> [u8; 32] {
+    let mut n_prime = Vec::with_capacity(b"key-blind".len() + 16);
+    n_prime.extend_from_slice(b"key-blind");
+    n_prime.extend_from_slice(&period.interval_num().to_be_bytes());
+    n_prime.extend_from_slice(&period.length().as_secs().to_be_bytes());
+
+    let mut h = Sha3_256::new();
+    h.update(b"Derive temporary signing key");
+    h.update([0u8]);
+    h.update(id_pub.as_ref().as_bytes());
+    // `s`, the optional secret seed, is unsupported and always empty: nothing to hash.
+    h.update(ED25519_BASEPOINT_COMPRESSED.as_bytes());
+    h.update(&n_prime);
+    h.finalize().into()
+}
+
+/// Clamp `bytes` per the standard Ed25519 scalar-clamping rule, and interpret the result as a
+/// [`Scalar`].
+///
+/// Clamping (clearing the low 3 bits and the top bit, and setting the second-highest bit)
+/// guarantees the resulting value is well below the group order, so unlike a general
+/// `Scalar::from_bytes_mod_order`, no further reduction is needed.
+fn clamped_scalar(mut bytes: [u8; 32]) -> Scalar {
+    bytes[0] &= 0b1111_1000;
+    bytes[31] &= 0b0111_1111;
+    bytes[31] |= 0b0100_0000;
+    Scalar::from_bits(bytes)
+}
+
+/// Derive the blinded signing keypair (`KS_hs_blind_id`) for `period` from the master
+/// identity keypair.
+///
+/// Implements the rest of the derivation started by [`blinding_factor`]: clamps `h`,
+/// scalar-multiplies the identity's already-clamped secret scalar by it to get the blinded
+/// secret scalar `a' = h * a`, and derives a matching nonce prefix so the blinded expanded
+/// secret key can sign the same way an ordinary one would.
+fn blind_keypair(
+    identity: HsIdKeypair,
+    id_pub: &HsIdKey,
+    period: TimePeriod,
+) -> Result<HsBlindIdKeypair, Bug> {
+    let h = blinding_factor(period, id_pub);
+    let h_scalar = clamped_scalar(h);
+
+    let identity: ed25519::ExpandedKeypair = identity.into();
+    let identity_bytes = identity.secret.to_bytes();
+    let mut a_bytes = [0_u8; 32];
+    a_bytes.copy_from_slice(&identity_bytes[..32]);
+    let nonce_prefix = &identity_bytes[32..];
+
+    // `a_bytes` is the scalar half of the *expanded* secret key, ie the seed hash with
+    // clamping already applied -- so, like `h` above, it's safe to read as a `Scalar`
+    // without an extra reduction.
+    let a_scalar = Scalar::from_bits(a_bytes);
+    let blinded_scalar = h_scalar * a_scalar;
+    let blinded_point = &blinded_scalar * &ED25519_BASEPOINT_TABLE;
+
+    // Domain-separated from `blinding_factor`'s hash (and from each other, via the leading
+    // tag byte) so that the blinded nonce can't be confused with the blinded pubkey hash or
+    // derived for the wrong time period.
+    let blinded_nonce: [u8; 32] = {
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"Derive temporary signing key");
+        hasher.update([2_u8]);
+        hasher.update(nonce_prefix);
+        hasher.update(h);
+        hasher.finalize().into()
+    };
+
+    let mut expanded_bytes = [0_u8; 64];
+    expanded_bytes[..32].copy_from_slice(blinded_scalar.as_bytes());
+    expanded_bytes[32..].copy_from_slice(&blinded_nonce);
+
+    let secret = ed25519::ExpandedSecretKey::from_bytes(&expanded_bytes)
+        .map_err(|cause| internal!("derived an invalid blinded secret key: {cause}"))?;
+    let public = ed25519::PublicKey::from_bytes(blinded_point.compress().as_bytes())
+        .map_err(|cause| internal!("derived an invalid blinded public key: {cause}"))?;
+
+    Ok(HsBlindIdKeypair::from(ed25519::ExpandedKeypair {
+        secret,
+        public,
+    }))
+}
+
 impl OnionService {
     /// Create (but do not launch) a new onion service.
     //
@@ -144,7 +752,10 @@ impl OnionService {
 
         let (rend_req_tx, rend_req_rx) = mpsc::channel(32);
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (shutdown_signal_tx, shutdown_signal_rx) = postage::watch::channel_with(false);
         let (config_tx, config_rx) = postage::watch::channel_with(Arc::new(config));
+        let (status_tx, status_rx) =
+            postage::watch::channel_with(OnionServiceStatus::new_bootstrapping());
 
         // TODO HSS: How do I give ipt_mgr_view to ipt_mgr?  Does IptManager even take
         //          one of these?
@@ -170,9 +781,25 @@ impl OnionService {
 
         maybe_generate_hsid(&keymgr, &nickname, offline_hsid)?;
 
+        let key_watcher = KeyWatcher {
+            runtime: runtime.clone(),
+            keymgr: keymgr.clone(),
+            nickname: nickname.clone(),
+            offline_hsid,
+            shutdown_signal: shutdown_signal_rx.clone(),
+        };
+
+        let blind_key_rotator = BlindKeyRotator {
+            runtime: runtime.clone(),
+            keymgr: keymgr.clone(),
+            nickname: nickname.clone(),
+            offline_hsid,
+            shutdown_signal: shutdown_signal_rx.clone(),
+        };
+
         let publisher: Publisher<R, publish::Real<R>> = Publisher::new(
             runtime,
-            nickname,
+            nickname.clone(),
             netdir_provider,
             circ_pool,
             publisher_view,
@@ -180,21 +807,28 @@ impl OnionService {
             Arc::clone(&keymgr),
         );
 
-        // TODO HSS: we need to actually do something with: shutdown_tx,
-        // rend_req_rx.  The latter may need to be refactored to actually work
-        // with svc::rend_handshake, if it doesn't already.
+        // TODO HSS: we need to actually do something with: rend_req_rx. It may need to be
+        // refactored to actually work with svc::rend_handshake, if it doesn't already.
 
         Ok(Arc::new(OnionService {
             inner: Mutex::new(SvcInner {
                 config_tx,
-                shutdown_tx,
+                nickname: nickname.clone(),
                 keymgr,
+                status: OnionServiceStatus::new_bootstrapping(),
+                status_tx,
+                status_rx,
+                shutdown_tx: Some(shutdown_tx),
+                shutdown_signal_tx,
+                shutdown_signal_rx,
                 unlaunched: Some((
                     rend_req_rx,
                     Box::new(ForLaunch {
                         publisher,
                         ipt_mgr,
                         ipt_mgr_view,
+                        key_watcher,
+                        blind_key_rotator,
                     }),
                 )),
             }),
@@ -227,18 +861,85 @@ impl OnionService {
         // connections, but existing ones.
     }
 
-    /// Tell this onion service about some new short-term keys it can use.
-    pub fn add_keys(&self, keys: ()) -> Result<(), Bug> {
-        todo!() // TODO hss
+    /// Authorize `client_id` to discover and rendezvous with this service, via `client_key`.
+    ///
+    /// This configures Tor v3 "client authorization" (a.k.a. "restricted
+    /// discovery"): `client_key` is the client's `x25519` public key,
+    /// and only clients whose key is authorized this way can decrypt the
+    /// inner layer of our descriptor (and so learn our introduction
+    /// points) or complete a rendezvous with us.
+    ///
+    /// Updates the keystore immediately, without requiring a restart.
+    ///
+    /// # Limitations
+    ///
+    /// As of this writing, this method only stores `client_key` in the keystore;
+    /// nothing else in this crate reads it back yet. Descriptors are not encrypted
+    /// to authorized clients, and rendezvous requests from clients with no key
+    /// here are not rejected, so calling this does not yet actually restrict
+    /// discovery or rendezvous to anyone. Don't rely on it for access control
+    /// until enforcement lands (see the `TODO HSS` below).
+    //
+    // TODO HSS: wire up enforcement. That needs `OnionServiceConfig` to grow an
+    // authorized-client set that `reconfigure()` can apply, the publisher to
+    // encrypt the descriptor's inner layer to that set, and the `rend_req_rx`
+    // pipeline to start enforcing it (dropping handshakes from clients with no
+    // key here, and exposing an `authorized_client` field on `RendRequest`).
+    pub fn add_client_key(
+        &self,
+        client_id: ClientId,
+        client_key: HsClientAuthKey,
+    ) -> Result<(), ClientAuthError> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        let spec = HsClientAuthKeySpecifier::new(&inner.nickname, client_id);
+        inner
+            .keymgr
+            .insert(client_key, &spec, KeystoreSelector::Default)
+            .map_err(ClientAuthError::Keystore)?;
+        Ok(())
+    }
+
+    /// Revoke `client_id`'s authorization to discover and rendezvous with this service.
+    ///
+    /// See [`add_client_key`](Self::add_client_key) (including its `# Limitations`).
+    /// Does nothing (and does not error) if `client_id` wasn't authorized in the
+    /// first place.
+    pub fn revoke_client_key(&self, client_id: ClientId) -> Result<(), ClientAuthError> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        let spec = HsClientAuthKeySpecifier::new(&inner.nickname, client_id);
+        inner
+            .keymgr
+            .remove::<HsClientAuthKey>(&spec)
+            .map_err(ClientAuthError::Keystore)?;
+        Ok(())
     }
 
     /// Return the current status of this onion service.
     pub fn status(&self) -> OnionServiceStatus {
-        todo!() // TODO hss
+        self.inner.lock().expect("poisoned lock").status.clone()
     }
 
-    // TODO hss let's also have a function that gives you a stream of Status
-    // changes?  Or use a publish-based watcher?
+    /// Return a stream that yields this onion service's status every time it changes.
+    ///
+    /// The stream does not yield the current status retroactively when subscribing;
+    /// call [`status`](Self::status) first if you need that.
+    pub fn status_events(&self) -> impl Stream<Item = OnionServiceStatus> {
+        self.inner.lock().expect("poisoned lock").status_rx.clone()
+    }
+
+    /// Update the status snapshot, and broadcast the change to every `status_events()`
+    /// subscriber.
+    //
+    // TODO HSS: call this from `IptManager` whenever the number of established
+    // introduction points changes, and from `Publisher` after each descriptor upload
+    // attempt, once those modules exist in this crate; for now nothing calls it but
+    // `stop`.
+    fn update_status(&self, f: impl FnOnce(&mut OnionServiceStatus)) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        f(&mut inner.status);
+        let new_status = inner.status.clone();
+        let _ = inner.status_tx.try_maybe_send(|_| Ok(new_status));
+    }
 
     /// Tell this onion service to begin running, and return a
     /// stream of rendezvous requests on the service.
@@ -254,26 +955,42 @@ impl OnionService {
                 .ok_or(StartupError::AlreadyLaunched)?
         };
 
-        launch.launch()?;
-
-        // TODO HSS:  This needs to launch at least the following tasks:
-        //
-        // - If we decide to use separate disk-based key provisioning, a task to
-        //   monitor our keys directory.
-        // - If we own our identity key, a task to generate per-period sub-keys as
-        //   needed.
+        launch.launch(Arc::downgrade(self))?;
 
         Ok(rend_req_rx)
     }
 
+    /// Re-send the current configuration to every task that's listening for config changes,
+    /// without actually changing anything.
+    ///
+    /// Used by the [`KeyWatcher`] to nudge the publisher (and anything else watching
+    /// `config_tx`) into re-reading key material that changed out from under it, such as a
+    /// freshly provisioned `KS_hs_id`.
+    fn trigger_republish(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let _ = inner
+            .config_tx
+            .try_maybe_send(|cur_config| Ok(Arc::clone(cur_config)));
+    }
+
     /// Tell this onion service to stop running.
     ///
-    /// It can be restarted with launch().
+    /// Transitions the status to [`OnionServiceState::Stopped`] (so `status_events()`
+    /// subscribers can react) and fires the shutdown signals that our background tasks are
+    /// waiting on: both the oneshot `IptManager` holds, and the watch-channel the
+    /// [`KeyWatcher`] and [`BlindKeyRotator`] loops poll alongside their sleeps.
     ///
     /// You can also shut down an onion service completely by dropping the last
     /// Clone of it.
+    //
+    // TODO HSS: "It can be restarted with launch()" was true of the old stub, but nothing
+    // here currently un-sets the Stopped state or gives `launch` a way to resume; decide
+    // whether `stop` is meant to be permanent.
     pub fn stop(&self) {
-        todo!() // TODO hss
+        self.update_status(|status| status.state = OnionServiceState::Stopped);
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.shutdown_tx.take();
+        let _ = inner.shutdown_signal_tx.try_maybe_send(|_| Ok(true));
     }
 }
 
@@ -374,6 +1091,7 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
 
+    use curve25519_dalek::edwards::CompressedEdwardsY;
     use fs_mistrust::Mistrust;
     use tempfile::{tempdir, TempDir};
 
@@ -547,4 +1265,59 @@ mod test {
 
         assert!(maybe_generate_hsid(&keymgr, &nickname, false /* offline_hsid */).is_err());
     }
+
+    #[test]
+    fn blind_keypair_is_internally_consistent() {
+        let (hsid_keypair, hsid_public) = create_hsid();
+        let period = TimePeriod::new(ASSUMED_TIME_PERIOD_LENGTH, SystemTime::now(), Duration::ZERO);
+
+        let blinded = blind_keypair(hsid_keypair, &hsid_public, period).unwrap();
+        let blinded: ed25519::ExpandedKeypair = blinded.into();
+
+        // Recompute the blinded public key the other way: by scalar-multiplying the
+        // identity's *public* point by `h`, rather than deriving it from the blinded
+        // secret scalar. The two must agree, since blinding is a group homomorphism:
+        // `h * (a * B) == (h * a) * B`.
+        let h_scalar = clamped_scalar(blinding_factor(period, &hsid_public));
+        let identity_point = CompressedEdwardsY(*hsid_public.as_ref().as_bytes())
+            .decompress()
+            .unwrap();
+        let expected_point = identity_point * h_scalar;
+
+        assert_eq!(
+            blinded.public.as_bytes(),
+            expected_point.compress().as_bytes()
+        );
+    }
+
+    #[test]
+    fn blinding_factor_hashes_the_fields_rend_spec_v3_specifies() {
+        // Unlike `blind_keypair_is_internally_consistent` (a self-consistency check that
+        // would pass even if `blinding_factor`'s hash input didn't match the real protocol
+        // at all), this independently reconstructs the exact byte sequence rend-spec-v3
+        // appendix A.2 specifies -- `H(BLIND_STRING | A | s | B | N)` -- without calling
+        // `blinding_factor`, and checks the two agree. It would have caught this function
+        // having omitted the basepoint `B` entirely.
+        //
+        // This is still not a substitute for an official known-answer test vector (or a
+        // cross-check against another independent implementation): it only proves
+        // `blinding_factor` matches *this* reading of the spec, not that this reading is
+        // correct. No such vector is available in this checkout (no network access, and
+        // none already present in the tree); adding one is still outstanding.
+        let (_hsid_keypair, hsid_public) = create_hsid();
+        let period = TimePeriod::new(ASSUMED_TIME_PERIOD_LENGTH, SystemTime::now(), Duration::ZERO);
+
+        let mut expected = Sha3_256::new();
+        expected.update(b"Derive temporary signing key"); // BLIND_STRING, part 1
+        expected.update([0u8]); // BLIND_STRING, part 2: INT_1(0)
+        expected.update(hsid_public.as_ref().as_bytes()); // A
+        // s (secret seed) is unsupported and empty: nothing to hash.
+        expected.update(ED25519_BASEPOINT_COMPRESSED.as_bytes()); // B
+        expected.update(b"key-blind");
+        expected.update(period.interval_num().to_be_bytes());
+        expected.update(period.length().as_secs().to_be_bytes()); // N
+        let expected: [u8; 32] = expected.finalize().into();
+
+        assert_eq!(blinding_factor(period, &hsid_public), expected);
+    }
 }
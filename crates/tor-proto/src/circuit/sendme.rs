@@ -10,6 +10,7 @@
 //! other side of the circuit really has read all of the data that it's
 //! acknowledging.
 
+use futures::channel::oneshot;
 use futures::lock::Mutex;
 
 use std::collections::VecDeque;
@@ -17,23 +18,81 @@ use std::sync::Arc;
 
 use tor_cell::relaycell::msg::RelayMsg;
 use tor_cell::relaycell::RelayCell;
+use tracing::debug;
 
 use crate::{Error, Result};
 
-// XXXX Three problems with this tag:
-// XXXX - First, we need to support unauthenticated flow control.
-// XXXX - Second, this tag type could be different for each layer, if we
-// XXXX   eventually have an authenticator that isn't 20 bytes long.
-// XXXX - Third, we want the comparison to happen with a constant-time
-// XXXX   operation.
+// XXXX We need to support unauthenticated flow control.
+
+/// A circuit or stream authenticator tag, used to confirm that a SENDME cell really does
+/// acknowledge data that the other side has read.
+///
+/// Implementations must compare tags in constant time: an early-exit comparison would leak
+/// timing information about how many leading bytes of a guess matched a real tag.
+pub(crate) trait FlowCtrlTag: Clone + std::fmt::Debug {
+    /// Return true if `self` and `other` are the same tag.
+    fn matches(&self, other: &Self) -> bool;
+}
 
 /// Tag type used in regular v1 sendme cells.
-pub(crate) type CircTag = [u8; 20];
+///
+/// This is a circuit authenticator: it proves that the other side of the circuit really
+/// has read all of the data it's acknowledging. Wrapped in a newtype (rather than a bare
+/// `[u8; 20]`) so that [`FlowCtrlTag::matches`], below, can compare in constant time instead
+/// of the derived `PartialEq`'s early-exit byte-by-byte comparison.
+#[derive(Clone, Debug)]
+pub(crate) struct CircTagV1([u8; 20]);
+
+impl From<[u8; 20]> for CircTagV1 {
+    fn from(tag: [u8; 20]) -> Self {
+        CircTagV1(tag)
+    }
+}
+
+impl FlowCtrlTag for CircTagV1 {
+    fn matches(&self, other: &Self) -> bool {
+        let mut difference = 0_u8;
+        for (a, b) in self.0.iter().zip(other.0.iter()) {
+            difference |= a ^ b;
+        }
+        difference == 0
+    }
+}
+
+/// A wider circuit authenticator tag, for a future authenticator that isn't 20 bytes long.
+///
+/// Not used by any negotiated protocol version yet; exists so a circuit can eventually pick
+/// a wider tag at construction time without a second copy of the windowing subsystem.
+#[derive(Clone, Debug)]
+pub(crate) struct CircTagWide<const N: usize>([u8; N]);
+
+impl<const N: usize> From<[u8; N]> for CircTagWide<N> {
+    fn from(tag: [u8; N]) -> Self {
+        CircTagWide(tag)
+    }
+}
+
+impl<const N: usize> FlowCtrlTag for CircTagWide<N> {
+    fn matches(&self, other: &Self) -> bool {
+        let mut difference = 0_u8;
+        for (a, b) in self.0.iter().zip(other.0.iter()) {
+            difference |= a ^ b;
+        }
+        difference == 0
+    }
+}
+
 /// Absence of a tag, as with stream cells.
 pub(crate) type NoTag = ();
 
+impl FlowCtrlTag for NoTag {
+    fn matches(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
 /// A circuit's send window.
-pub(crate) type CircSendWindow = SendWindow<CircParams, CircTag>;
+pub(crate) type CircSendWindow = SendWindow<CircParams, CircTagV1>;
 /// A stream's send window.
 pub(crate) type StreamSendWindow = SendWindow<StreamParams, NoTag>;
 
@@ -50,7 +109,7 @@ pub(crate) type StreamRecvWindow = RecvWindow<StreamParams>;
 pub(crate) struct SendWindow<P, T>
 where
     P: WindowParams,
-    T: PartialEq + Eq + Clone,
+    T: FlowCtrlTag,
 {
     // TODO could use a bilock if that becomes non-experimental.
     // TODO I wish we could do this without locking; we could make a bunch
@@ -64,15 +123,73 @@ where
 /// Interior (locked) code for SendWindowInner.
 struct SendWindowInner<T>
 where
-    T: PartialEq + Eq + Clone,
+    T: FlowCtrlTag,
 {
     /// Current value for this window
     window: u16,
-    /// Tag values that incoming "SENDME" messages need to match in order
-    /// for us to send more data.
+    /// Tag values recorded for every cell we've sent, oldest first.
+    ///
+    /// [`SendWindow::take`] pushes one entry per cell, unconditionally -- even when the
+    /// circuit negotiated unauthenticated flow control and we don't expect to ever get a
+    /// matching tag back -- so that if a SENDME does arrive with a tag, or we decide to
+    /// start enforcing authentication, we already have the expected values on hand.
+    /// [`SendWindow::put`] drains one [`WindowParams::increment`]-sized batch off the front
+    /// per SENDME, and checks the tag of the last cell in that batch: that's the cell whose
+    /// digest a real SENDME for that batch would echo back.
     tags: VecDeque<T>,
-    /// An event to wait on if we find that we are out of cells.
-    unblock: event_listener::Event,
+    /// How many incoming SENDMEs arrived with no tag (`put(None)`) while we had one queued
+    /// up in `tags` that we would have checked had flow control been authenticated.
+    ///
+    /// Purely a migration/telemetry aid: it lets an operator measure how many peers
+    /// claiming no FlowCtrl support would have failed tag authentication, before flipping
+    /// enforcement on.
+    unauthenticated_puts_seen: u64,
+    /// Tasks parked in [`SendWindow::take`], waiting for window space to free up.
+    waiters: WaitQueue,
+}
+
+/// A FIFO queue of tasks waiting for [`SendWindow`] space to free up.
+///
+/// Each waiter parks behind a one-shot channel instead of a broadcast-style event: when
+/// `put` frees `n` cells, it wakes exactly the `n` waiters that have been parked the
+/// longest, by sending on their channels, rather than waking everyone and leaving all but
+/// one to immediately re-lock the mutex, find nothing left, and re-park (a thundering
+/// herd).
+#[derive(Default)]
+struct WaitQueue(VecDeque<oneshot::Sender<()>>);
+
+impl WaitQueue {
+    /// Register a new waiter, returning the receiver it should await.
+    fn park(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.0.push_back(tx);
+        rx
+    }
+
+    /// Wake up to `n` waiters, in the order they parked.
+    ///
+    /// A waiter whose `take()` call was cancelled (so its receiver was
+    /// dropped without ever being polled to completion) left a dead
+    /// `Sender` sitting in the queue; waking it would silently waste one of
+    /// the `n` wake-ups on nobody, starving a live waiter behind it even
+    /// though a slot is available. So a dead entry is discarded instead of
+    /// counting towards `n`, and we keep going until `n` *live* waiters
+    /// have been woken or the queue runs dry.
+    fn wake(&mut self, mut n: usize) {
+        while n > 0 {
+            let Some(tx) = self.0.pop_front() else {
+                break;
+            };
+            if tx.send(()).is_ok() {
+                n -= 1;
+            }
+        }
+    }
+
+    /// How many tasks are currently parked, waiting for window space.
+    fn len(&self) -> usize {
+        self.0.len()
+    }
 }
 
 /// Helper: parameterizes a window to determine its maximum and its increment.
@@ -111,16 +228,17 @@ impl WindowParams for StreamParams {
 impl<P, T> SendWindow<P, T>
 where
     P: WindowParams,
-    T: PartialEq + Eq + Clone,
+    T: FlowCtrlTag,
 {
     /// Construct a new SendWindow.
     pub(crate) fn new(window: u16) -> SendWindow<P, T> {
-        let increment = P::increment();
-        let capacity = (window + increment - 1) / increment;
+        // One tag per cell (see `SendWindowInner::tags`), so the queue can hold as many
+        // entries as the window allows before a single SENDME drains a batch of them.
         let inner = SendWindowInner {
             window,
-            tags: VecDeque::with_capacity(capacity as usize),
-            unblock: event_listener::Event::new(),
+            tags: VecDeque::with_capacity(window as usize),
+            unauthenticated_puts_seen: 0,
+            waiters: WaitQueue::default(),
         };
         SendWindow {
             w: Arc::new(Mutex::new(inner)),
@@ -149,22 +267,24 @@ where
                 let mut w = self.w.lock().await;
                 if let Some(val) = w.window.checked_sub(1) {
                     w.window = val;
-                    if w.window % P::increment() == 0 {
-                        // We record this tag.
-                        // TODO: I'm not saying that this cell in particular
-                        // matches the spec, but Tor seems to like it.
-                        w.tags.push_back(tag.clone());
-                    }
+                    // Record every cell's tag, not just the one that happens to land on
+                    // an `increment`-cell boundary: see the doc comment on
+                    // `SendWindowInner::tags` for why, and `put` for how these get
+                    // consumed again.
+                    w.tags.push_back(tag.clone());
 
                     return Ok(val);
                 }
 
-                // Window is zero; can't send yet.
-                w.unblock.listen()
+                // Window is zero; can't send yet. Park ourselves at the back of the
+                // queue and wait to be granted a slot.
+                w.waiters.park()
             };
 
-            // Wait on this event while _not_ holding the lock.
-            wait_on.await;
+            // Wait to be granted a slot, while _not_ holding the lock. A cancellation
+            // error here would mean our `Sender` was dropped without being used, which
+            // `WaitQueue` never does, so there's nothing to check in the result.
+            let _ = wait_on.await;
         }
     }
 
@@ -172,7 +292,11 @@ where
     ///
     /// If the tag is None, then we don't enforce tag requirements. (We can
     /// remove this option once we no longer support getting SENDME cells
-    /// from relays without the FlowCtrl=1 protocol.)
+    /// from relays without the FlowCtrl=1 protocol.) We still pop the tag we were
+    /// expecting off the front of the queue, and if one was queued up, count it towards
+    /// [`unauthenticated_puts_seen`](Self::unauthenticated_puts_seen) as a migration
+    /// diagnostic: an operator can use that counter to see how often this would have
+    /// failed authentication, before turning enforcement on.
     ///
     /// On success, return the number of cells left in the window.
     ///
@@ -181,27 +305,60 @@ where
     #[must_use = "didn't check whether SENDME tag was right."]
     pub(crate) async fn put(&mut self, tag: Option<T>) -> Option<u16> {
         let mut w = self.w.lock().await;
-
-        match (w.tags.front(), tag) {
-            (Some(t), Some(tag)) if t == &tag => {} // this is the right tag.
-            (Some(_), None) => {}                   // didn't need a tag.
+        let increment = P::increment() as usize;
+
+        // A SENDME acks the whole `increment`-cell batch since the last one; the tag that
+        // actually matters is the last cell's in that batch (the one a real SENDME's digest
+        // would echo). Peek at it without draining yet, so a rejected tag leaves the whole
+        // batch queued for a later, correct put to still match.
+        let expected = w.tags.get(increment.saturating_sub(1)).cloned();
+
+        match (expected, tag) {
+            (Some(t), Some(tag)) if t.matches(&tag) => {} // this is the right tag.
+            (Some(_), None) => {
+                w.unauthenticated_puts_seen += 1;
+                // This is completely ordinary for a peer that hasn't negotiated
+                // authenticated flow control -- there's no tag to actually compare
+                // against, so it's not really a "mismatch" -- just a migration/telemetry
+                // data point, not something an operator needs to see by default.
+                debug!(
+                    "received a SENDME with no authentication tag; flow-control \
+                     enforcement is not yet on for this window"
+                );
+            } // didn't need a tag.
             _ => {
                 return None;
             } // Bad tag or unexpected sendme.
         }
-        w.tags.pop_front();
+        w.tags.drain(..increment);
 
         let was_zero = w.window == 0;
+        let increment = increment as u16;
 
-        let v = w.window.checked_add(P::increment())?;
+        let v = w.window.checked_add(increment)?;
         w.window = v;
 
         if was_zero {
-            w.unblock.notify(usize::MAX)
+            // Wake exactly as many waiters as the slots we just freed, in the order
+            // they parked: see `WaitQueue`.
+            w.waiters.wake(increment as usize);
         }
         Some(v)
     }
 
+    /// How many incoming SENDMEs have arrived via [`put`](Self::put)`(None)` while we had
+    /// a tag queued up that we would have checked, had this window's flow control been
+    /// authenticated.
+    pub(crate) async fn unauthenticated_puts_seen(&self) -> u64 {
+        self.w.lock().await.unauthenticated_puts_seen
+    }
+
+    /// How many tasks are currently parked in [`take`](Self::take), waiting for window
+    /// space to free up.
+    pub(crate) async fn waiters_parked(&self) -> usize {
+        self.w.lock().await.waiters.len()
+    }
+
     /// For testing: get a copy of the current send window, and the
     /// expected incoming tags.
     #[cfg(test)]
@@ -291,6 +448,12 @@ mod test {
     use tokio_crate as tokio;
     use tor_cell::relaycell::{msg, RelayCell};
 
+    impl FlowCtrlTag for &'static str {
+        fn matches(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+
     #[test]
     fn what_counts() {
         let m = msg::Begin::new("www.torproject.org", 443, 0)
@@ -341,31 +504,36 @@ mod test {
             w.take(&"world").await?;
         }
         assert_eq!(w.w.lock().await.window, 901);
-        assert_eq!(w.w.lock().await.tags.len(), 0);
+        // Every take() records its tag now, not just the 100th.
+        assert_eq!(w.w.lock().await.tags.len(), 99);
 
         let n = w.take(&"and").await?;
         assert_eq!(n, 900);
-        assert_eq!(w.w.lock().await.tags.len(), 1);
-        assert_eq!(w.w.lock().await.tags[0], "and");
+        // The 100th recorded tag -- the one a SENDME covering this batch would echo -- is
+        // "and": see `SendWindow::put`.
+        assert_eq!(w.w.lock().await.tags.len(), 100);
+        assert_eq!(w.w.lock().await.tags[99], "and");
 
         let n = w.take(&"goodbye").await?;
         assert_eq!(n, 899);
-        assert_eq!(w.w.lock().await.tags.len(), 1);
+        assert_eq!(w.w.lock().await.tags.len(), 101);
 
         // Try putting a good tag.
         let n = w.put(Some("and")).await;
         assert_eq!(n, Some(999));
-        assert_eq!(w.w.lock().await.tags.len(), 0);
+        // The whole acked batch (up to and including "and") is drained; only "goodbye",
+        // recorded after it, is left.
+        assert_eq!(w.w.lock().await.tags.len(), 1);
 
         for _ in 0_usize..300 {
             w.take(&"dreamland").await?;
         }
-        assert_eq!(w.w.lock().await.tags.len(), 3);
+        assert_eq!(w.w.lock().await.tags.len(), 301);
 
         // Put without a tag.
         let n = w.put(None).await;
         assert_eq!(n, Some(799));
-        assert_eq!(w.w.lock().await.tags.len(), 2);
+        assert_eq!(w.w.lock().await.tags.len(), 201);
 
         Ok(())
     }
@@ -399,6 +567,32 @@ mod test {
         Ok(())
     }
 
+    #[async_test]
+    async fn sendwindow_unauthenticated_puts_are_counted() -> Result<()> {
+        let mut w = new_sendwindow();
+        assert_eq!(w.unauthenticated_puts_seen().await, 0);
+
+        for _ in 0_usize..100 {
+            w.take(&"tag").await?;
+        }
+        assert_eq!(w.w.lock().await.tags.len(), 100);
+
+        // No tag supplied, even though one was queued up: this is the migration
+        // diagnostic path, so it's still accepted, but it gets counted.
+        let n = w.put(None).await;
+        assert_eq!(n, Some(1000));
+        assert_eq!(w.w.lock().await.tags.len(), 0);
+        assert_eq!(w.unauthenticated_puts_seen().await, 1);
+
+        // A put with no tag expected isn't counted as a migration diagnostic; it's just
+        // an unexpected sendme, and fails outright.
+        let n = w.put(None).await;
+        assert!(n.is_none());
+        assert_eq!(w.unauthenticated_puts_seen().await, 1);
+
+        Ok(())
+    }
+
     #[async_test]
     async fn sendwindow_blocking() -> Result<()> {
         let mut w = new_sendwindow();
@@ -410,8 +604,44 @@ mod test {
         // This is going to block -- make sure it doesn't say it's ready.
         let ready = w.take(&"there a string").now_or_never();
         assert!(ready.is_none());
+        assert_eq!(w.waiters_parked().await, 1);
 
         // TODO: test that this actually wakes up when somebody else says "put".
         Ok(())
     }
+
+    #[test]
+    fn wait_queue_wakes_exactly_n_in_fifo_order() {
+        let mut q = WaitQueue::default();
+        let rxs: Vec<_> = (0..5).map(|_| q.park()).collect();
+        assert_eq!(q.len(), 5);
+
+        q.wake(2);
+        assert_eq!(q.len(), 3);
+
+        let mut rxs = rxs.into_iter();
+        assert_eq!(rxs.next().unwrap().now_or_never(), Some(Ok(())));
+        assert_eq!(rxs.next().unwrap().now_or_never(), Some(Ok(())));
+        for rx in rxs {
+            assert!(rx.now_or_never().is_none());
+        }
+    }
+
+    #[test]
+    fn wait_queue_skips_cancelled_waiters_without_wasting_a_wake() {
+        let mut q = WaitQueue::default();
+        let rx0 = q.park();
+        // A waiter whose `take()` was cancelled: its receiver is dropped
+        // without ever being awaited to completion.
+        drop(q.park());
+        let rx2 = q.park();
+        assert_eq!(q.len(), 3);
+
+        // Ask for 2 wake-ups: the dead entry in the middle must not count
+        // against that budget, so both live waiters still get woken.
+        q.wake(2);
+        assert_eq!(q.len(), 0);
+        assert_eq!(rx0.now_or_never(), Some(Ok(())));
+        assert_eq!(rx2.now_or_never(), Some(Ok(())));
+    }
 }